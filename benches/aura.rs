@@ -0,0 +1,159 @@
+//! Replays random walks through the aura lookup of `Bureau::update_aura`, once scanning every user
+//! and once with `UserList::aura_changes`, checking both find the same enters and leaves.
+//! Every user owns a socket, so this needs an open file per user.
+//! Run with `cargo bench --bench aura`.
+
+use std::{
+	hint::black_box,
+	net,
+	time::{Duration, Instant},
+};
+
+use mio::{net::TcpStream, Interest, Poll, Token};
+use spark::bureau::{
+	codec::PositionUpdate, math::Vector3, send_queue::SendLimits, user_list::UserList,
+};
+
+const AURA_RADIUS: f32 = 300.0;
+const WORLD_SIZE: f32 = 5000.0;
+const UPDATES: usize = 50_000;
+
+struct Rng(u64);
+
+impl Rng {
+	fn next_f32(&mut self) -> f32 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		(self.0 >> 40) as f32 / (1u64 << 24) as f32
+	}
+
+	fn position(&mut self) -> Vector3 {
+		Vector3::new(
+			self.next_f32() * WORLD_SIZE,
+			self.next_f32() * 10.0,
+			self.next_f32() * WORLD_SIZE,
+		)
+	}
+}
+
+/// Fill a UserList with `users` Users, who all share one connection that nothing is sent over.
+fn user_list(users: usize, poll: &Poll) -> UserList {
+	let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let stream = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+	stream.set_nonblocking(true).unwrap();
+
+	// Nothing is flushed, so the queues have to hold every hello.
+	let limits = SendLimits {
+		high_water: usize::MAX,
+		hard_limit: usize::MAX,
+	};
+	let mut user_list = UserList::new(users as i32, AURA_RADIUS, limits);
+	for _ in 0..users {
+		let mut socket = TcpStream::from_std(stream.try_clone().unwrap());
+		poll.registry()
+			.register(&mut socket, Token(0), Interest::READABLE)
+			.unwrap();
+		assert!(user_list.add(socket, poll.registry()));
+	}
+
+	user_list
+}
+
+/// Move `id` the way a client does, by sending a position update.
+fn move_user(user_list: &mut UserList, id: i32, pos: Vector3) {
+	let user = user_list.get_mut(&id).unwrap();
+	user.receive(&PositionUpdate::new(id, pos).encode().bytes);
+}
+
+/// Find the aura changes of `id` by checking every other user, as `update_aura` did before the grid.
+fn scan(user_list: &UserList, id: i32) -> (Vec<i32>, Vec<(f32, i32)>) {
+	let user = &user_list[&id];
+
+	let mut leaves = Vec::new();
+	let mut enters = Vec::new();
+	for other in user_list.values().filter(|other| other.id != id) {
+		let distance = user.pos().distance(other.pos());
+
+		if user.aura.contains(&other.id) {
+			if distance > AURA_RADIUS {
+				leaves.push(other.id);
+			}
+		} else if distance <= AURA_RADIUS {
+			enters.push((distance, other.id));
+		}
+	}
+
+	enters.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+	(leaves, enters)
+}
+
+fn bench(users: usize) {
+	let mut rng = Rng(0x2545F4914F6CDD1D ^ users as u64);
+	let poll = Poll::new().unwrap();
+	let mut user_list = user_list(users, &poll);
+	for id in 1..=users as i32 {
+		move_user(&mut user_list, id, rng.position());
+		user_list.moved(id);
+	}
+
+	let mut scan_time = Duration::ZERO;
+	let mut grid_time = Duration::ZERO;
+	let mut aura_size = 0;
+
+	for _ in 0..UPDATES {
+		let id = (rng.next_f32() * users as f32) as i32 % users as i32 + 1;
+		let pos = user_list[&id].pos();
+		let pos = Vector3::new(
+			(pos.x + (rng.next_f32() - 0.5) * 40.0).clamp(0.0, WORLD_SIZE),
+			pos.y,
+			(pos.z + (rng.next_f32() - 0.5) * 40.0).clamp(0.0, WORLD_SIZE),
+		);
+		move_user(&mut user_list, id, pos);
+
+		let start = Instant::now();
+		let (mut scan_leaves, mut scan_enters) = scan(&user_list, id);
+		scan_time += start.elapsed();
+
+		let start = Instant::now();
+		let (mut leaves, mut enters) = user_list.aura_changes(id, AURA_RADIUS, AURA_RADIUS);
+		grid_time += start.elapsed();
+
+		// Users at the same distance may enter in either order.
+		scan_leaves.sort_unstable();
+		leaves.sort_unstable();
+		scan_enters.sort_by_key(|enter| enter.1);
+		enters.sort_by_key(|enter| enter.1);
+		assert!(
+			scan_leaves == leaves && scan_enters == enters,
+			"grid produced different aura changes than a scan"
+		);
+
+		for other_id in leaves {
+			user_list.get_mut(&id).unwrap().aura.remove(&other_id);
+			user_list.get_mut(&other_id).unwrap().aura.remove(&id);
+		}
+		for (_, other_id) in enters {
+			user_list.get_mut(&id).unwrap().aura.insert(other_id);
+			user_list.get_mut(&other_id).unwrap().aura.insert(id);
+		}
+
+		aura_size += black_box(&user_list[&id].aura).len();
+	}
+
+	println!(
+		"{:>5} users, {:>5.1} in aura on average: scan {:>8.2?}/update, grid {:>8.2?}/update, {:.1}x faster",
+		users,
+		aura_size as f32 / UPDATES as f32,
+		scan_time / UPDATES as u32,
+		grid_time / UPDATES as u32,
+		scan_time.as_secs_f64() / grid_time.as_secs_f64()
+	);
+}
+
+fn main() {
+	for users in [50, 200, 1000, 5000] {
+		bench(users);
+	}
+}
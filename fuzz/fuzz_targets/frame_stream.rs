@@ -0,0 +1,118 @@
+#![no_main]
+
+use std::{net, ops::Range, sync::OnceLock};
+
+use libfuzzer_sys::fuzz_target;
+use mio::net::TcpStream;
+use spark::bureau::{
+	codec::{Common, CommonMessage, Frame, GeneralMessage, Message},
+	frame_reader::FrameReader,
+	send_queue::SendLimits,
+	user::User,
+};
+
+/// A connected socket for the User to own, nothing is ever read from or written to it.
+fn socket() -> TcpStream {
+	static PEER: OnceLock<(net::TcpListener, net::TcpStream)> = OnceLock::new();
+
+	let (_, stream) = PEER.get_or_init(|| {
+		let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let stream = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		(listener, stream)
+	});
+
+	let stream = stream.try_clone().unwrap();
+	stream.set_nonblocking(true).unwrap();
+	TcpStream::from_std(stream)
+}
+
+/// Bytes of a frame holding int32floats, which are allowed to come back slightly different.
+fn int32floats(frame: &Frame) -> Range<usize> {
+	match frame {
+		Frame::PositionUpdate(_) => 13..25,
+		Frame::GeneralMessage(GeneralMessage {
+			message:
+				Message::MsgCommon(CommonMessage {
+					content: Common::TransformUpdate { .. },
+					..
+				}),
+			..
+		}) => 26..74,
+		_ => 0..0,
+	}
+}
+
+/// Check that `encoded` is `bytes` again, only int32floats may be slightly off.
+fn assert_same(frame: &Frame, bytes: &[u8], encoded: &[u8]) {
+	assert_eq!(
+		encoded.len(),
+		bytes.len(),
+		"length of {:02x?} changed",
+		bytes
+	);
+
+	let floats = int32floats(frame);
+	assert_eq!(encoded[..floats.start], bytes[..floats.start]);
+	assert_eq!(encoded[floats.end..], bytes[floats.end..]);
+
+	// An f32 only has 24 bits of precision, so large values may be off by a bit more than that.
+	for (a, b) in bytes[floats.clone()]
+		.chunks_exact(4)
+		.zip(encoded[floats].chunks_exact(4))
+	{
+		let a = i32::from_be_bytes(a.try_into().unwrap()) as i64;
+		let b = i32::from_be_bytes(b.try_into().unwrap()) as i64;
+		assert!(
+			(a - b).abs() <= (a.abs() >> 22) + 1,
+			"int32float {} became {}",
+			a,
+			b
+		);
+	}
+}
+
+/// Check that a decoded frame is encoded back into the bytes it came from.
+/// Bytes after the content of a known type are dropped, so those frames only have to stay the same
+/// once they've been encoded.
+fn assert_round_trip(bytes: &[u8]) {
+	let Ok(frame) = Frame::decode(bytes) else {
+		return;
+	};
+	let encoded = frame.encode().bytes;
+
+	if encoded.len() < bytes.len() {
+		let frame = Frame::decode(&encoded).expect("encoded frame can't be decoded");
+		assert_same(&frame, &encoded, &frame.encode().bytes);
+	} else {
+		assert_same(&frame, bytes, &encoded);
+	}
+}
+
+// Feeds a byte stream to a User like `User::poll` does with what it reads from the socket,
+// the first byte decides how the stream is split up to mimic partial socket reads.
+fuzz_target!(|data: &[u8]| {
+	let Some((chunk_size, stream)) = data.split_first() else {
+		return;
+	};
+	let chunk_size = *chunk_size as usize % 64 + 1;
+
+	let limits = SendLimits {
+		high_water: 64 * 1024,
+		hard_limit: 1024 * 1024,
+	};
+	let mut user = User::new(1, socket(), limits).unwrap();
+	// Only used to find the frames to check round trips with, the User splits the stream itself.
+	let mut frame_reader = FrameReader::new();
+
+	for chunk in stream.chunks(chunk_size) {
+		user.receive(chunk);
+		if !user.connected {
+			return;
+		}
+
+		frame_reader.extend(chunk);
+		while let Ok(Some(frame)) = frame_reader.next_frame() {
+			assert_round_trip(&frame);
+		}
+	}
+});
@@ -1,48 +1,122 @@
 use std::{
+	collections::{HashMap, VecDeque},
 	io::{ErrorKind, Read},
-	net::{SocketAddr, TcpListener, TcpStream},
-	thread,
+	net::SocketAddr,
 	time::{Duration, Instant},
 };
 
+use mio::{
+	net::{TcpListener, TcpStream},
+	Events, Interest, Poll, Token,
+};
+
 use super::{
+	character::CharacterData,
+	codec::{Common, GeneralMessage, Message, PositionUpdate},
+	emulated_master::{EmulatedMaster, MasterReply, ReplyTarget},
 	lua_api::LuaApi,
+	master::MasterPolicy,
 	math::{Mat3, Vector3},
-	protocol::{ByteWriter, MsgCommon, Opcode, Strategy},
+	plugin::{self, HookStats, PluginOptions},
+	protocol::{ByteWriter, Strategy},
+	send_queue::{Coalesce, SendLimits},
+	storage,
+	timer_wheel::TimerWheel,
 	user::UserEvent,
 	user_list::UserList,
 };
 
+/// How often `hook.onThink` is ran.
+const THINK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a socket has to send its hello before being dropped.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LISTENER: Token = Token(0);
+// Users are registered with their id as the token, so connecting sockets use tokens above any id.
+const CONNECTING_BASE: usize = 1 << 31;
+
 #[derive(Clone, Copy)]
 pub struct BureauOptions {
 	pub max_players: i32,
+	/// Distance at which two users enter each others aura.
 	pub aura_radius: f32,
+	/// Distance at which two users leave each others aura, should be at least `aura_radius`.
+	pub aura_leave_radius: f32,
+	/// Most users an aura may hold, the closest ones are kept.
+	pub max_aura: Option<usize>,
+	pub master_policy: MasterPolicy,
+	/// Disconnect users that have been asleep for this long.
+	pub sleep_timeout: Option<Duration>,
+	/// Answer requests to the master from `Bureau::emulated_master` and Lua before asking a client.
+	pub emulate_master: bool,
+	/// Pass CharacterUpdates that can't be parsed on unchanged instead of dropping them.
+	pub pass_invalid_characters: bool,
+	pub send_limits: SendLimits,
+	pub plugins: PluginOptions,
+}
+
+/// A socket that has connected but not yet sent its hello.
+struct Connecting {
+	socket: TcpStream,
+	hello: Vec<u8>,
 }
 
 pub struct Bureau {
 	pub user_list: UserList,
 	pub options: BureauOptions,
+	pub emulated_master: EmulatedMaster,
 
 	port: u16,
+	poll: Poll,
+	events: Events,
 	listener: TcpListener,
-	connecting: Vec<(Instant, Option<TcpStream>)>,
+	connecting: HashMap<Token, Connecting>,
+	next_token: usize,
+	timeouts: TimerWheel<Token>,
+	/// Users that had more to read than a single poll takes, read from in turns.
+	readable: VecDeque<i32>,
+	last_think: Instant,
+	/// Plugin reloads requested through SIGHUP that have been handled.
+	reloads: usize,
 	lua_api: LuaApi,
 }
 
 impl Bureau {
 	pub fn new(addr: SocketAddr, options: BureauOptions) -> anyhow::Result<Self> {
-		let listener = TcpListener::bind(addr)?;
-		listener.set_nonblocking(true)?;
+		Self::with_listener(TcpListener::bind(addr)?, options)
+	}
+
+	/// Create a Bureau that accepts users from an already bound listener.
+	pub fn with_listener(
+		mut listener: TcpListener,
+		options: BureauOptions,
+	) -> anyhow::Result<Self> {
+		let poll = Poll::new()?;
 
-		let lua_api = LuaApi::new()?;
+		poll.registry()
+			.register(&mut listener, LISTENER, Interest::READABLE)?;
+
+		let lua_api = LuaApi::new(options.plugins)?;
 
 		Ok(Self {
-			user_list: UserList::new(options.max_players),
+			user_list: UserList::new(
+				options.max_players,
+				options.aura_radius,
+				options.send_limits,
+			),
 			options,
+			emulated_master: EmulatedMaster::default(),
 
 			port: listener.local_addr()?.port(),
+			poll,
+			events: Events::with_capacity(1024),
 			listener,
-			connecting: Vec::new(),
+			connecting: HashMap::new(),
+			next_token: CONNECTING_BASE,
+			timeouts: TimerWheel::new(Duration::from_millis(100), 128),
+			readable: VecDeque::new(),
+			last_think: Instant::now(),
+			reloads: plugin::reloads(),
 			lua_api,
 		})
 	}
@@ -51,84 +125,245 @@ impl Bureau {
 		self.port
 	}
 
+	/// How long plugin hooks have taken to run, keyed by event.
+	pub fn hook_stats(&self) -> HashMap<&'static str, HookStats> {
+		self.lua_api.hook_stats()
+	}
+
+	/// Time until the Bureau next needs to be polled, even if no sockets become ready.
+	pub fn next_timeout(&self) -> Duration {
+		if !self.readable.is_empty() {
+			return Duration::ZERO;
+		}
+
+		let now = Instant::now();
+
+		let mut deadline = self.last_think + THINK_INTERVAL;
+		if let Some(timeout) = self.timeouts.next_deadline() {
+			deadline = deadline.min(timeout);
+		}
+		if let Some(timer) = self.lua_api.next_timer() {
+			deadline = deadline.min(timer);
+		}
+
+		deadline.saturating_duration_since(now)
+	}
+
 	pub fn run(&mut self) -> ! {
 		loop {
-			self.poll();
-			thread::sleep(Duration::from_millis(100));
+			self.poll(Some(self.next_timeout()));
 		}
 	}
 
-	pub fn poll(&mut self) {
-		if let Ok((socket, addr)) = self.listener.accept() {
-			if self.lua_api.user_connect(addr) {
-				if let Ok(()) = socket.set_nonblocking(true) {
-					self.connecting.push((Instant::now(), Some(socket)));
+	/// Wait up to `timeout` for socket events and handle them.
+	/// Returns true if the event buffer was filled and more events may be waiting.
+	pub fn poll(&mut self, timeout: Option<Duration>) -> bool {
+		if let Err(err) = self.poll.poll(&mut self.events, timeout) {
+			if err.kind() != ErrorKind::Interrupted {
+				eprintln!("Failed to poll Bureau: {}", err);
+			}
+		}
+
+		let events = self
+			.events
+			.iter()
+			.map(|e| (e.token(), e.is_readable(), e.is_writable()))
+			.collect::<Vec<_>>();
+		for (token, readable, writable) in events.iter().copied() {
+			match token {
+				LISTENER => self.accept(),
+				Token(t) if t >= CONNECTING_BASE => self.hello(token),
+				Token(id) => {
+					if readable {
+						self.user_readable(id as i32);
+					}
+					if writable {
+						if let Some(user) = self.user_list.get_mut(&(id as i32)) {
+							user.flush();
+						}
+					}
 				}
 			}
 		}
 
-		self.lua_api.think();
+		self.read_users();
 
-		self.connecting.retain_mut(|(connect_time, socket)| {
-			let mut hello_buf = [0; 7];
-			let n = match socket.as_mut().unwrap().read(&mut hello_buf) {
-				Ok(n) => n,
-				Err(e) if e.kind() == ErrorKind::WouldBlock => {
-					return connect_time.elapsed().as_secs() <= 10;
-				}
-				Err(_) => return false,
-			};
+		for token in self.timeouts.expire(Instant::now()) {
+			// Sockets that already finished their hello are no longer in the map.
+			self.connecting.remove(&token);
+		}
+
+		if self.last_think.elapsed() >= THINK_INTERVAL {
+			self.last_think = Instant::now();
+			self.lua_api.think();
+			self.disconnect_sleepers();
+			storage::flush();
+		}
 
-			let socket = socket.take().unwrap();
+		self.lua_api.run_timers();
 
-			if n < 7 {
-				return false;
+		self.lua_api.run_events(&mut self.user_list);
+
+		let reloads = plugin::reloads();
+		if reloads != self.reloads {
+			self.reloads = reloads;
+			self.lua_api.reload(&self.user_list);
+		}
+
+		let keys = self.user_list.keys().copied().collect::<Vec<i32>>();
+		for id in keys {
+			let user = self.user_list.get(&id).unwrap();
+			if !user.connected {
+				self.disconnect_user(id);
 			}
+		}
+
+		if self.user_list.remove_disconnected() {
+			self.user_list.send_user_count();
+		}
+
+		for user in self.user_list.values_mut() {
+			user.flush();
+		}
 
-			for (i, b) in hello_buf.iter().enumerate() {
-				// Last two bytes are vscp version.
-				if *b != b"hello\x01\x01"[i] {
-					return false;
+		events.len() == self.events.capacity()
+	}
+
+	fn disconnect_sleepers(&mut self) {
+		let Some(timeout) = self.options.sleep_timeout else {
+			return;
+		};
+
+		for user in self.user_list.values_mut() {
+			if user.connected && user.asleep_for(timeout) {
+				eprintln!(
+					"User {} was asleep for too long and will be disconnected.",
+					user.id
+				);
+				user.connected = false;
+			}
+		}
+	}
+
+	fn accept(&mut self) {
+		loop {
+			let (mut socket, addr) = match self.listener.accept() {
+				Ok(accepted) => accepted,
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(e) => {
+					eprintln!("Failed to accept connection: {}", e);
+					return;
 				}
+			};
+
+			if !self.lua_api.user_connect(addr) {
+				continue;
 			}
 
-			if self.user_list.add(socket) {
-				self.user_list.send_user_count();
+			let token = Token(self.next_token);
+			self.next_token = self.next_token.checked_add(1).unwrap_or(CONNECTING_BASE);
+
+			if self
+				.poll
+				.registry()
+				.register(&mut socket, token, Interest::READABLE)
+				.is_err()
+			{
+				continue;
 			}
 
-			false
-		});
+			self.connecting.insert(
+				token,
+				Connecting {
+					socket,
+					hello: Vec::with_capacity(7),
+				},
+			);
+			self.timeouts.insert_after(HELLO_TIMEOUT, token);
+		}
+	}
 
-		let keys = self.user_list.keys().copied().collect::<Vec<i32>>();
-		for id in keys.iter().copied() {
-			let user = self.user_list.get_mut(&id).unwrap();
+	fn hello(&mut self, token: Token) {
+		let Some(connecting) = self.connecting.get_mut(&token) else {
+			return;
+		};
 
-			if let Some(event) = user.poll() {
-				self.handle_event(id, event);
+		let mut hello_buf = [0; 7];
+		loop {
+			let remaining = 7 - connecting.hello.len();
+			match connecting.socket.read(&mut hello_buf[..remaining]) {
+				Ok(0) => {
+					self.connecting.remove(&token);
+					return;
+				}
+				Ok(n) => {
+					connecting.hello.extend_from_slice(&hello_buf[..n]);
+					if connecting.hello.len() == 7 {
+						break;
+					}
+				}
+				Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+				Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+				Err(_) => {
+					self.connecting.remove(&token);
+					return;
+				}
 			}
 		}
 
-		self.lua_api.run_events(&mut self.user_list);
+		let connecting = self.connecting.remove(&token).unwrap();
 
-		for id in keys {
-			let user = self.user_list.get(&id).unwrap();
-			if !user.connected {
-				self.disconnect_user(id);
-			}
+		// Last two bytes are vscp version.
+		if connecting.hello != b"hello\x01\x01" {
+			return;
 		}
 
-		let mut removed = false;
-		self.user_list.retain(|_, user| {
-			removed |= !user.connected;
-			user.connected
-		});
-		if removed {
+		if self.user_list.add(connecting.socket, self.poll.registry()) {
 			self.user_list.send_user_count();
 		}
 	}
 
+	fn user_readable(&mut self, id: i32) {
+		let Some(user) = self.user_list.get_mut(&id) else {
+			return;
+		};
+
+		// Sockets are edge triggered, so the User is read from until it would block.
+		user.readable = true;
+		if !self.readable.contains(&id) {
+			self.readable.push_back(id);
+		}
+	}
+
+	/// Poll every readable User once, the ones that still have more to read are polled again next time.
+	/// This keeps one User that floods the Bureau from starving everyone else.
+	fn read_users(&mut self) {
+		for _ in 0..self.readable.len() {
+			let Some(id) = self.readable.pop_front() else {
+				break;
+			};
+			let Some(user) = self.user_list.get_mut(&id) else {
+				continue;
+			};
+			if !user.connected || !user.readable {
+				continue;
+			}
+
+			for event in user.poll() {
+				self.handle_event(id, event);
+			}
+
+			if let Some(user) = self.user_list.get_mut(&id) {
+				if user.connected && user.readable {
+					self.readable.push_back(id);
+				}
+			}
+		}
+	}
+
 	fn send_to_all(&mut self, stream: &ByteWriter) {
-		for (_, user) in self.user_list.iter_mut() {
+		for user in self.user_list.values_mut() {
 			user.send(stream);
 		}
 	}
@@ -145,94 +380,192 @@ impl Bureau {
 		});
 	}
 
+	fn send_to_aura_coalesced(&mut self, id: i32, key: Coalesce, stream: &ByteWriter) {
+		self.user_list.for_aura(id, |_, other| {
+			other.send_coalesced(key, stream);
+		});
+	}
+
 	fn update_aura(&mut self, id: i32) {
-		self.user_list.for_others(id, |user, other| {
-			let in_radius =
-				user.pos().distance_sqr(other.pos()) <= self.options.aura_radius.powi(2);
-			let in_aura = user.aura.contains(&other.id);
-
-			if !in_radius && in_aura {
-				other.aura.remove(&user.id);
-				other.send(&ByteWriter::general_message(
-					other.id,
-					user.id,
-					Opcode::SMsgUserLeft,
-					&ByteWriter::new(4).write_i32(user.id).bytes,
-				));
-
-				user.aura.remove(&other.id);
-				user.send(&ByteWriter::general_message(
-					user.id,
-					other.id,
-					Opcode::SMsgUserLeft,
-					&ByteWriter::new(4).write_i32(other.id).bytes,
-				));
-
-				self.lua_api.aura_leave(user.id, other.id);
-			} else if in_radius && !in_aura {
-				other.aura.insert(user.id);
-				other.send(&ByteWriter::general_message(
-					other.id,
-					other.id,
-					Opcode::SMsgUserJoined,
-					&ByteWriter::new(8)
-						.write_i32(user.id)
-						.write_i32(user.id)
-						.write_string(&user.avatar)
-						.write_string(&user.username)
-						.bytes,
-				));
-				other.send(&ByteWriter::message_common(
-					other.id,
-					user.id,
-					MsgCommon::CharacterUpdate,
+		let (leaves, enters) = self.user_list.aura_changes(
+			id,
+			self.options.aura_radius,
+			self.options.aura_leave_radius,
+		);
+
+		for other_id in leaves {
+			self.leave_aura(id, other_id);
+		}
+
+		// Closest first, so they get priority when auras are full.
+		for (distance, other_id) in enters {
+			let (Some(evict), Some(other_evict)) = (
+				self.make_aura_room(id, distance),
+				self.make_aura_room(other_id, distance),
+			) else {
+				continue;
+			};
+
+			for (id, evicted) in [(id, evict), (other_id, other_evict)] {
+				if let Some(evicted) = evicted {
+					self.leave_aura(id, evicted);
+				}
+			}
+
+			self.enter_aura(id, other_id);
+		}
+	}
+
+	/// Check if the aura of `id` has room for someone `distance` away.
+	/// Returns who has to be removed to make room, `Some(None)` if there already is room,
+	/// or `None` if everyone in the aura is closer.
+	fn make_aura_room(&self, id: i32, distance: f32) -> Option<Option<i32>> {
+		let Some(max_aura) = self.options.max_aura else {
+			return Some(None);
+		};
+
+		let user = self.user_list.get(&id)?;
+		if user.aura.len() < max_aura {
+			return Some(None);
+		}
+
+		let (furthest_distance, furthest) = user
+			.aura
+			.iter()
+			.filter_map(|other_id| {
+				let other = self.user_list.get(other_id)?;
+				Some((user.pos().distance(other.pos()), *other_id))
+			})
+			.max_by(|a, b| a.0.total_cmp(&b.0))?;
+
+		// Use the same margin as the radii, so two users at about the same distance don't keep swapping.
+		let margin = match self.options.aura_radius > 0.0 {
+			true => (self.options.aura_leave_radius / self.options.aura_radius).max(1.0),
+			false => 1.0,
+		};
+		if furthest_distance <= distance * margin {
+			return None;
+		}
+
+		Some(Some(furthest))
+	}
+
+	/// Add two users to each others aura.
+	fn enter_aura(&mut self, id: i32, other_id: i32) {
+		for (receiver, subject) in [(id, other_id), (other_id, id)] {
+			let subject_user = &self.user_list[&subject];
+			let joined = GeneralMessage::new(receiver, subject, subject_user.joined()).encode();
+			let character = subject_user.character.as_ref().map(|character| {
+				GeneralMessage::common(
+					receiver,
+					subject,
 					Strategy::AuraClientsExceptSender,
-					&ByteWriter::new(0).write_string(&user.data).bytes,
-				));
-
-				user.aura.insert(other.id);
-				user.send(&ByteWriter::general_message(
-					user.id,
-					other.id,
-					Opcode::SMsgUserJoined,
-					&ByteWriter::new(8)
-						.write_i32(other.id)
-						.write_i32(other.id)
-						.write_string(&other.avatar)
-						.write_string(&other.username)
-						.bytes,
-				));
-				user.send(&ByteWriter::message_common(
-					user.id,
-					other.id,
-					MsgCommon::CharacterUpdate,
+					Common::CharacterUpdate(character.to_string()),
+				)
+				.encode()
+			});
+
+			let vc_register = subject_user.vc_register.as_ref().map(|data| {
+				GeneralMessage::common(
+					subject,
+					subject,
 					Strategy::AuraClientsExceptSender,
-					&ByteWriter::new(0).write_string(&other.data).bytes,
-				));
-
-				self.lua_api.aura_enter(user.id, other.id);
+					Common::VcRegister(data.clone()),
+				)
+				.encode()
+			});
+			let voice_state = subject_user
+				.voice_state
+				.as_ref()
+				.filter(|_| !subject_user.voice_muted)
+				.map(|data| {
+					GeneralMessage::common(
+						subject,
+						subject,
+						Strategy::AuraClientsExceptSender,
+						Common::VoiceState(data.clone()),
+					)
+					.encode()
+				});
+
+			let receiver_user = self.user_list.get_mut(&receiver).unwrap();
+			receiver_user.aura.insert(subject);
+			receiver_user.send(&joined);
+			for stream in [character, vc_register, voice_state].into_iter().flatten() {
+				receiver_user.send(&stream);
 			}
-		});
+		}
+
+		self.lua_api.aura_enter(id, other_id);
+	}
+
+	/// Remove two users from each others aura.
+	fn leave_aura(&mut self, id: i32, other_id: i32) {
+		for (receiver, subject) in [(id, other_id), (other_id, id)] {
+			let Some(receiver_user) = self.user_list.get_mut(&receiver) else {
+				continue;
+			};
+
+			receiver_user.aura.remove(&subject);
+			receiver_user.send(
+				&GeneralMessage::new(receiver, subject, Message::SMsgUserLeft(subject)).encode(),
+			);
+		}
+
+		self.lua_api.aura_leave(id, other_id);
 	}
 
 	fn disconnect_user(&mut self, id: i32) {
 		self.user_list.for_aura(id, |_, other| {
 			other.aura.remove(&id);
-			other.send(&ByteWriter::general_message(
-				id,
-				id,
-				Opcode::SMsgUserLeft,
-				&ByteWriter::new(4).write_i32(id).bytes,
-			))
+			other.send(&GeneralMessage::new(id, id, Message::SMsgUserLeft(id)).encode())
 		});
 
+		if let Some(user) = self.user_list.get(&id) {
+			for other_id in user.aura.iter() {
+				self.lua_api.aura_leave(id, *other_id);
+			}
+		}
+
+		// Hand the role over before plugins forget about the old master.
+		if self.user_list.master() == Some(id) {
+			self.elect_master();
+		}
+
 		self.lua_api.user_disconnect(id);
 	}
 
+	/// Pick a new master if there is none, or the current one is disconnecting.
+	fn elect_master(&mut self) {
+		let current = self.user_list.master();
+		if current.is_some_and(|id| self.user_list.get(&id).is_some_and(|user| user.connected)) {
+			return;
+		}
+
+		let candidates = self.user_list.master_candidates();
+
+		let mut master = None;
+		if self.options.master_policy == MasterPolicy::Lua && !candidates.is_empty() {
+			master = self.lua_api.master_elect(&candidates);
+			if master.is_some_and(|id| !candidates.contains(&id)) {
+				eprintln!("hook.onMasterElect picked a user that can't be master.");
+				master = None;
+			}
+		}
+		let master = master.or_else(|| self.options.master_policy.choose(&candidates));
+
+		if master == current {
+			return;
+		}
+
+		self.user_list.set_master(master);
+		self.lua_api.master_change(master, current);
+	}
+
 	fn handle_event(&mut self, id: i32, event: UserEvent) {
 		match event {
 			UserEvent::NewUser(name, avatar) => self.new_user(id, name, avatar),
-			UserEvent::StateChange => (),
+			UserEvent::StateChange(state, old) => self.lua_api.state_change(id, state, old),
 			UserEvent::PositionUpdate(pos) => self.position_update(id, pos),
 			UserEvent::TransformUpdate(mat, pos) => self.transform_update(id, mat, pos),
 			UserEvent::ChatSend(msg) => self.chat_send(id, msg),
@@ -240,6 +573,8 @@ impl Bureau {
 			UserEvent::NameChange(name) => self.name_change(id, name),
 			UserEvent::AvatarChange(avatar) => self.avatar_change(id, avatar),
 			UserEvent::PrivateChat(receiver, msg) => self.private_chat(id, receiver, msg),
+			UserEvent::VcRegister(strategy, id2, data) => self.vc_register(id, strategy, id2, data),
+			UserEvent::VoiceState(data) => self.voice_state(id, data),
 			UserEvent::ApplSpecific(strategy, id2, method, strarg, intarg) => {
 				self.appl_specific(id, strategy, id2, method, strarg, intarg)
 			}
@@ -247,16 +582,21 @@ impl Bureau {
 	}
 
 	fn new_user(&mut self, id: i32, name: String, avatar: String) {
-		self.user_list.master();
 		self.user_list.send_user_count();
 
 		let ip = self.user_list.get(&id).unwrap().addr().ip();
 		self.lua_api.new_user(id, &name, &avatar, ip);
+
+		self.elect_master();
 	}
 
 	fn position_update(&mut self, id: i32, pos: Vector3) {
 		self.update_aura(id);
-		self.send_to_aura(id, &ByteWriter::position_update(id, &pos));
+		self.send_to_aura_coalesced(
+			id,
+			Coalesce::Position(id),
+			&PositionUpdate::new(id, pos.clone()).encode(),
+		);
 
 		self.lua_api.pos_update(id, &pos);
 	}
@@ -264,26 +604,19 @@ impl Bureau {
 	fn transform_update(&mut self, id: i32, rot: Mat3, pos: Vector3) {
 		self.update_aura(id);
 
-		let mut transform_update = ByteWriter::new(12 * 4);
-
-		for f in rot.data {
-			transform_update = transform_update.write_f32(f);
-		}
-
-		transform_update = transform_update
-			.write_f32(pos.x)
-			.write_f32(pos.y)
-			.write_f32(pos.z);
-
-		self.send_to_aura(
+		self.send_to_aura_coalesced(
 			id,
-			&ByteWriter::message_common(
+			Coalesce::Transform(id),
+			&GeneralMessage::common(
 				id,
 				id,
-				MsgCommon::TransformUpdate,
 				Strategy::AuraClients,
-				&transform_update.bytes,
-			),
+				Common::TransformUpdate {
+					rotation: rot.clone(),
+					position: pos,
+				},
+			)
+			.encode(),
 		);
 
 		self.lua_api.trans_update(id, &rot);
@@ -302,39 +635,66 @@ impl Bureau {
 
 		self.send_to_aura(
 			id,
-			&ByteWriter::message_common(
+			&GeneralMessage::common(
 				id,
 				id,
-				MsgCommon::ChatSend,
 				Strategy::AllClientsExceptSender,
-				&ByteWriter::new(text.len() + 1).write_string(&text).bytes,
-			),
+				Common::ChatSend(text),
+			)
+			.encode(),
 		);
 	}
 
 	fn character_update(&mut self, id: i32, data: String) {
-		self.send_to_aura(
-			id,
-			&ByteWriter::message_common(
-				id,
+		let Some(mut character) = CharacterData::parse(&data) else {
+			if !self.options.pass_invalid_characters {
+				eprintln!("User {} sent invalid character data: {:?}.", id, data);
+				return;
+			}
+
+			// Only the current aura gets it, so that nobody is sent the old data later on.
+			self.user_list.get_mut(&id).unwrap().character = None;
+			self.send_to_aura(
 				id,
-				MsgCommon::CharacterUpdate,
-				Strategy::AuraClientsExceptSender,
-				&ByteWriter::new(data.len() + 1).write_string(&data).bytes,
-			),
-		);
+				&GeneralMessage::common(
+					id,
+					id,
+					Strategy::AuraClientsExceptSender,
+					Common::CharacterUpdate(data),
+				)
+				.encode(),
+			);
+			return;
+		};
+
+		match self.lua_api.character_update(id, &character) {
+			Some((false, _)) => return,
+			Some((true, Some(new_character))) => character = new_character,
+			_ => (),
+		}
+
+		let stream = GeneralMessage::common(
+			id,
+			id,
+			Strategy::AuraClientsExceptSender,
+			Common::CharacterUpdate(character.to_string()),
+		)
+		.encode();
+
+		self.user_list.get_mut(&id).unwrap().character = Some(character);
+		self.send_to_aura(id, &stream);
 	}
 
 	fn name_change(&mut self, id: i32, name: String) {
 		self.send_to_aura(
 			id,
-			&ByteWriter::message_common(
+			&GeneralMessage::common(
 				id,
 				id,
-				MsgCommon::NameChange,
 				Strategy::AuraClientsExceptSender,
-				&ByteWriter::new(name.len() + 1).write_string(&name).bytes,
-			),
+				Common::NameChange(name.clone()),
+			)
+			.encode(),
 		);
 
 		self.lua_api.name_change(id, &name);
@@ -343,15 +703,13 @@ impl Bureau {
 	fn avatar_change(&mut self, id: i32, avatar: String) {
 		self.send_to_aura(
 			id,
-			&ByteWriter::message_common(
+			&GeneralMessage::common(
 				id,
 				id,
-				MsgCommon::AvatarChange,
 				Strategy::AuraClientsExceptSender,
-				&ByteWriter::new(avatar.len() + 1)
-					.write_string(&avatar)
-					.bytes,
-			),
+				Common::AvatarChange(avatar.clone()),
+			)
+			.encode(),
 		);
 
 		self.lua_api.avatar_change(id, &avatar);
@@ -390,13 +748,18 @@ impl Bureau {
 			return;
 		};
 
-		other.send(&ByteWriter::message_common(
-			id,
-			id,
-			MsgCommon::PrivateChat,
-			Strategy::SpecificClient,
-			&ByteWriter::new(0).write_i32(id).write_string(&text).bytes,
-		))
+		other.send(
+			&GeneralMessage::common(
+				id,
+				id,
+				Strategy::SpecificClient,
+				Common::PrivateChat {
+					broadcast_id: id,
+					message: text,
+				},
+			)
+			.encode(),
+		)
 	}
 
 	fn appl_specific(
@@ -404,22 +767,43 @@ impl Bureau {
 		id: i32,
 		strategy: Strategy,
 		id2: i32,
-		method: String,
-		strarg: String,
-		intarg: i32,
+		mut method: String,
+		mut strarg: String,
+		mut intarg: i32,
 	) {
-		let stream = ByteWriter::message_common(
+		match self
+			.lua_api
+			.appl_specific(id, strategy, id2, &method, &strarg, intarg)
+		{
+			Some((false, ..)) => return,
+			Some((true, new_method, new_strarg, new_intarg)) => {
+				method = new_method.unwrap_or(method);
+				strarg = new_strarg.unwrap_or(strarg);
+				intarg = new_intarg.unwrap_or(intarg);
+			}
+			None => (),
+		}
+
+		if id2 == -9999
+			&& matches!(strategy, Strategy::SpecificClient)
+			&& self.options.emulate_master
+			&& self.answer_as_master(id, &method, &strarg, intarg)
+		{
+			return;
+		}
+
+		let stream = GeneralMessage::common(
 			id,
 			id2,
-			MsgCommon::ApplSpecific,
 			strategy,
-			&ByteWriter::new(1)
-				.write_u8(2)
-				.write_string(&method)
-				.write_string(&strarg)
-				.write_i32(intarg)
-				.bytes,
-		);
+			Common::ApplSpecific {
+				unknown: 2,
+				method,
+				strarg,
+				intarg,
+			},
+		)
+		.encode();
 
 		if id2 == -9999 {
 			match strategy {
@@ -431,9 +815,8 @@ impl Bureau {
 				| Strategy::AllClientsExceptSender
 				| Strategy::Unknown6 => self.send_to_other(id, &stream),
 				Strategy::SpecificClient => {
-					let master_id = match self.user_list.master() {
-						Some(master_id) => master_id,
-						None => return,
+					let Some(master_id) = self.user_list.master() else {
+						return;
 					};
 
 					let Some(user) = self.user_list.get_mut(&master_id) else {
@@ -442,28 +825,117 @@ impl Bureau {
 
 					user.send(&stream);
 				}
-				Strategy::Invalid => (),
+				Strategy::Other(_) => (),
 			}
 
 			return;
 		}
 
+		self.send_with_strategy(id, strategy, id2, &stream);
+	}
+
+	fn vc_register(&mut self, id: i32, strategy: Strategy, id2: i32, data: Vec<u8>) {
+		if !self.lua_api.vc_register(id) {
+			return;
+		}
+
+		let stream =
+			GeneralMessage::common(id, id2, strategy, Common::VcRegister(data.clone())).encode();
+
+		self.user_list.get_mut(&id).unwrap().vc_register = Some(data);
+		self.send_with_strategy(id, strategy, id2, &stream);
+	}
+
+	fn voice_state(&mut self, id: i32, data: Vec<u8>) {
+		if !self.lua_api.voice_state(id) {
+			return;
+		}
+
+		// Kept while muted so it can be sent once the user is unmuted.
+		let user = self.user_list.get_mut(&id).unwrap();
+		user.voice_state = Some(data.clone());
+		if user.voice_muted {
+			return;
+		}
+
+		self.send_to_aura(
+			id,
+			&GeneralMessage::common(
+				id,
+				id,
+				Strategy::AuraClientsExceptSender,
+				Common::VoiceState(data),
+			)
+			.encode(),
+		);
+	}
+
+	/// Send a message from `id` to whoever `strategy` says it's for, `id2` being the receiver of
+	/// `Strategy::SpecificClient`.
+	fn send_with_strategy(&mut self, id: i32, strategy: Strategy, id2: i32, stream: &ByteWriter) {
 		match strategy {
 			Strategy::AuraClients => {
-				self.send_to_aura(id, &stream);
-				self.user_list.get_mut(&id).unwrap().send(&stream);
+				self.send_to_aura(id, stream);
+				self.user_list.get_mut(&id).unwrap().send(stream);
 			}
-			Strategy::AuraClientsExceptSender => self.send_to_aura(id, &stream),
+			Strategy::AuraClientsExceptSender => self.send_to_aura(id, stream),
 			Strategy::SpecificClient => {
 				let Some(target) = self.user_list.get_mut(&id2) else {
 					return;
 				};
 
-				target.send(&stream);
+				target.send(stream);
 			}
-			Strategy::AllClients => self.send_to_all(&stream),
-			Strategy::AllClientsExceptSender => self.send_to_other(id, &stream),
+			Strategy::AllClients => self.send_to_all(stream),
+			Strategy::AllClientsExceptSender => self.send_to_other(id, stream),
 			_ => (),
 		}
 	}
+
+	/// Answer a request meant for the master client in its place.
+	/// Returns false if neither Lua nor `emulated_master` know how to.
+	fn answer_as_master(&mut self, id: i32, method: &str, strarg: &str, intarg: i32) -> bool {
+		let replies = match self.lua_api.master_request(id, method, strarg, intarg) {
+			Some(replies) => replies,
+			None => match self.emulated_master.replies(method) {
+				Some(replies) => replies.to_vec(),
+				None => return false,
+			},
+		};
+
+		for reply in replies {
+			self.send_master_reply(id, reply);
+		}
+
+		true
+	}
+
+	fn send_master_reply(&mut self, id: i32, reply: MasterReply) {
+		let (broadcast_id, strategy) = match reply.target {
+			ReplyTarget::Sender => (id, Strategy::SpecificClient),
+			ReplyTarget::All => (-9999, Strategy::AllClients),
+		};
+
+		let stream = GeneralMessage::common(
+			id,
+			broadcast_id,
+			strategy,
+			Common::ApplSpecific {
+				unknown: 2,
+				method: reply.method,
+				strarg: reply.strarg,
+				intarg: reply.intarg.unwrap_or(id),
+			},
+		)
+		.encode();
+
+		match reply.target {
+			ReplyTarget::Sender => {
+				if let Some(user) = self.user_list.get_mut(&id) {
+					user.send(&stream);
+				}
+			}
+			ReplyTarget::All => self.send_to_all(&stream),
+		}
+	}
 }
@@ -0,0 +1,173 @@
+use std::fmt;
+
+/// Medal awarded for spending enough time with an avatar.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Medal {
+	None = 0,
+	Happy = 1,
+	Lucky = 2,
+	Lovely = 3,
+}
+
+impl Medal {
+	pub fn from_u8(value: u8) -> Option<Self> {
+		match value {
+			0 => Some(Medal::None),
+			1 => Some(Medal::Happy),
+			2 => Some(Medal::Lucky),
+			3 => Some(Medal::Lovely),
+			_ => None,
+		}
+	}
+}
+
+/// Colour and scale of one body part, each sent as a single base 36 digit so both are below 36.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct BodyPart {
+	pub color: u8,
+	pub scale: u8,
+}
+
+/// Parsed CharacterUpdate data, such as `sleep:0 1:000000000000:58:0:`.
+/// Only the canonical form is accepted, so parsing and displaying it again gives back the same text.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CharacterData {
+	pub sleep: bool,
+	pub avatar: u32,
+	pub parts: Vec<BodyPart>,
+	/// Minutes spent using this avatar.
+	pub minutes: u32,
+	/// Not checked against `minutes`, that's left to plugins.
+	pub medal: Medal,
+}
+
+/// Parse a number without a sign or leading zeros.
+fn parse_number(s: &str) -> Option<u32> {
+	if s.is_empty() || (s.len() > 1 && s.starts_with('0')) || !s.bytes().all(|b| b.is_ascii_digit())
+	{
+		return None;
+	}
+
+	s.parse().ok()
+}
+
+/// Parse a single lowercase base 36 digit.
+fn parse_digit(b: u8) -> Option<u8> {
+	match b {
+		b'0'..=b'9' | b'a'..=b'z' => (b as char).to_digit(36).map(|d| d as u8),
+		_ => None,
+	}
+}
+
+impl CharacterData {
+	pub fn parse(s: &str) -> Option<Self> {
+		let rest = s.strip_prefix("sleep:")?;
+		let (sleep, rest) = rest.split_once(' ')?;
+		let sleep = match sleep {
+			"0" => false,
+			"1" => true,
+			_ => return None,
+		};
+
+		let fields = rest.strip_suffix(':')?.split(':').collect::<Vec<_>>();
+		let [avatar, parts, minutes, medal] = fields[..] else {
+			return None;
+		};
+
+		if parts.len() % 2 != 0 {
+			return None;
+		}
+		let parts = parts
+			.as_bytes()
+			.chunks_exact(2)
+			.map(|pair| {
+				Some(BodyPart {
+					color: parse_digit(pair[0])?,
+					scale: parse_digit(pair[1])?,
+				})
+			})
+			.collect::<Option<Vec<_>>>()?;
+
+		Some(Self {
+			sleep,
+			avatar: parse_number(avatar)?,
+			parts,
+			minutes: parse_number(minutes)?,
+			medal: Medal::from_u8(parse_number(medal)?.try_into().ok()?)?,
+		})
+	}
+}
+
+impl fmt::Display for CharacterData {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "sleep:{} {}:", self.sleep as u8, self.avatar)?;
+
+		for part in &self.parts {
+			for digit in [part.color, part.scale] {
+				write!(f, "{}", char::from_digit(digit as u32, 36).unwrap_or('0'))?;
+			}
+		}
+
+		write!(f, ":{}:{}:", self.minutes, self.medal as u8)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		for data in [
+			"sleep:0 1:000000000000:58:0:",
+			"sleep:1 1:000000000000:58:0:",
+			"sleep:0 12::0:0:",
+			"sleep:0 3:1a2z09zz:4000000000:3:",
+		] {
+			let character = CharacterData::parse(data).unwrap();
+			assert_eq!(character.to_string(), data);
+		}
+
+		let character = CharacterData::parse("sleep:1 7:0z1a:120:2:").unwrap();
+		assert!(character.sleep);
+		assert_eq!(character.avatar, 7);
+		assert!(
+			character.parts
+				== [
+					BodyPart {
+						color: 0,
+						scale: 35
+					},
+					BodyPart {
+						color: 1,
+						scale: 10
+					}
+				]
+		);
+		assert_eq!(character.minutes, 120);
+		assert!(character.medal == Medal::Lucky);
+	}
+
+	#[test]
+	fn not_canonical() {
+		for data in [
+			"",
+			"sleep:0 1:000000000000:58:0",
+			"sleep:0 1:000000000000:58:0::",
+			"sleep:0 1:000000000000:58:",
+			"sleep:2 1:000000000000:58:0:",
+			"sleep:0  1:000000000000:58:0:",
+			"sleep:0 01:000000000000:58:0:",
+			"sleep:0 -1:000000000000:58:0:",
+			"sleep:0 +1:000000000000:58:0:",
+			"sleep:0 1:00000000000:58:0:",
+			"sleep:0 1:00000000000A:58:0:",
+			"sleep:0 1:000000000000:4294967296:0:",
+			"sleep:0 1:000000000000:58:4:",
+			"sleep:0 1:000000000000:58:256:",
+		] {
+			assert!(CharacterData::parse(data).is_none(), "{:?}", data);
+		}
+	}
+}
@@ -0,0 +1,586 @@
+use super::{
+	math::{Mat3, Vector3},
+	protocol::{ByteReader, ByteWriter, DecodeError, MsgCommon, Opcode, Strategy},
+};
+
+// Typed versions of every packet in VSCP.md, types that aren't understood yet keep their raw content.
+// Everything that's decoded is encoded back into the same bytes, except that int32floats are read
+// into an f32 and only come back the same while they're at most 256 either way, and bytes after the
+// content of a known type are ignored like the original bureau does.
+
+/// A complete packet, starting with its section type.
+#[derive(Clone)]
+pub enum Frame {
+	/// Section type `0`, also known as a Sys0 Message.
+	GeneralMessage(GeneralMessage),
+	/// Section type `1`, purpose unknown.
+	Sys1([u8; 14]),
+	/// Section type `2`, also known as a Sys2 Message.
+	PositionUpdate(PositionUpdate),
+}
+
+#[derive(Clone)]
+pub struct GeneralMessage {
+	pub id1: i32,
+	pub id2: i32,
+	pub message: Message,
+}
+
+/// Content of a General Message, one variant per opcode.
+#[derive(Clone)]
+pub enum Message {
+	CMsgNewUser {
+		username: String,
+		avatar: String,
+	},
+	SMsgClientId(i32),
+	SMsgUserJoined {
+		broadcast_id: i32,
+		unknown: i32,
+		avatar: String,
+		username: String,
+	},
+	SMsgUserLeft(i32),
+	SMsgBroadcastId(i32),
+	MsgCommon(CommonMessage),
+	CMsgStateChange(u8),
+	SMsgSetMaster(bool),
+	SMsgUserCount {
+		/// Always set to `1`.
+		unknown: u8,
+		count: i32,
+	},
+	Unknown {
+		opcode: u32,
+		content: Vec<u8>,
+	},
+}
+
+#[derive(Clone)]
+pub struct CommonMessage {
+	pub broadcast_id: i32,
+	pub strategy: Strategy,
+	pub content: Common,
+}
+
+/// Content of a MsgCommon, one variant per type.
+#[derive(Clone)]
+pub enum Common {
+	TransformUpdate {
+		rotation: Mat3,
+		position: Vector3,
+	},
+	ChatSend(String),
+	CharacterUpdate(String),
+	NameChange(String),
+	AvatarChange(String),
+	PrivateChat {
+		broadcast_id: i32,
+		message: String,
+	},
+	VcRegister(Vec<u8>),
+	VoiceState(Vec<u8>),
+	Unknown19(Vec<u8>),
+	ApplSpecific {
+		/// Seems to always be set to `2`.
+		unknown: u8,
+		method: String,
+		strarg: String,
+		intarg: i32,
+	},
+	Unknown {
+		msg_type: u32,
+		content: Vec<u8>,
+	},
+}
+
+#[derive(Clone)]
+pub struct PositionUpdate {
+	pub connection_id: i32,
+	pub client_id: i32,
+	pub broadcast_id: i32,
+	pub position: Vector3,
+	/// Usually just `0x0100`.
+	pub unknown: [u8; 2],
+}
+
+impl Frame {
+	pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+		let mut reader = ByteReader::new(bytes);
+
+		let frame = match reader.read_u8()? {
+			0 => Self::GeneralMessage(GeneralMessage::read(&mut reader)?),
+			1 => Self::Sys1(reader.read_array()?),
+			2 => Self::PositionUpdate(PositionUpdate::read(&mut reader)?),
+			section_type => return Err(DecodeError::UnknownSectionType(section_type)),
+		};
+		reader.finish()?;
+
+		Ok(frame)
+	}
+
+	pub fn encode(&self) -> ByteWriter {
+		match self {
+			Self::GeneralMessage(msg) => msg.encode(),
+			Self::Sys1(data) => ByteWriter::new(15).write_u8(1).write_arr(data),
+			Self::PositionUpdate(update) => update.encode(),
+		}
+	}
+}
+
+impl GeneralMessage {
+	pub fn new(id1: i32, id2: i32, message: Message) -> Self {
+		Self { id1, id2, message }
+	}
+
+	/// Create a MsgCommon sent by `id1`, `id2` is the broadcast id in the MsgCommon header.
+	pub fn common(id1: i32, id2: i32, strategy: Strategy, content: Common) -> Self {
+		Self::new(
+			id1,
+			id1,
+			Message::MsgCommon(CommonMessage {
+				broadcast_id: id2,
+				strategy,
+				content,
+			}),
+		)
+	}
+
+	/// Read a General Message without its section type, consuming all of `reader`.
+	pub fn read(reader: &mut ByteReader) -> Result<Self, DecodeError> {
+		let id1 = reader.read_i32()?;
+		let id2 = reader.read_i32()?;
+		let opcode = reader.read_u32()?;
+		let size = reader.read_u32()?;
+		let content = reader.read_rest();
+		if content.len() != size as usize {
+			return Err(DecodeError::SizeMismatch {
+				declared: size,
+				actual: content.len(),
+			});
+		}
+
+		Ok(Self {
+			id1,
+			id2,
+			message: Message::decode(opcode, content)?,
+		})
+	}
+
+	pub fn encode(&self) -> ByteWriter {
+		let (opcode, content) = self.message.encode();
+
+		ByteWriter::new(17 + content.bytes.len())
+			.write_u8(0)
+			.write_i32(self.id1)
+			.write_i32(self.id2)
+			.write_u32(opcode)
+			.write_u32(content.bytes.len() as u32)
+			.write_arr(&content.bytes)
+	}
+}
+
+impl Message {
+	pub fn decode(opcode: u32, content: &[u8]) -> Result<Self, DecodeError> {
+		let mut reader = ByteReader::new(content);
+
+		let message = match opcode {
+			0 => Self::CMsgNewUser {
+				username: reader.read_string()?,
+				avatar: reader.read_string()?,
+			},
+			1 => Self::SMsgClientId(reader.read_i32()?),
+			2 => Self::SMsgUserJoined {
+				broadcast_id: reader.read_i32()?,
+				unknown: reader.read_i32()?,
+				avatar: reader.read_string()?,
+				username: reader.read_string()?,
+			},
+			3 => Self::SMsgUserLeft(reader.read_i32()?),
+			4 => Self::SMsgBroadcastId(reader.read_i32()?),
+			6 => Self::MsgCommon(CommonMessage::read(&mut reader)?),
+			7 => Self::CMsgStateChange(reader.read_u8()?),
+			8 if matches!(content, [0] | [1]) => Self::SMsgSetMaster(reader.read_u8()? == 1),
+			11 => Self::SMsgUserCount {
+				unknown: reader.read_u8()?,
+				count: reader.read_i32()?,
+			},
+			_ => Self::Unknown {
+				opcode,
+				content: reader.read_rest().to_vec(),
+			},
+		};
+
+		Ok(message)
+	}
+
+	/// Encode the content of the message, returning it along with its opcode.
+	pub fn encode(&self) -> (u32, ByteWriter) {
+		match self {
+			Self::CMsgNewUser { username, avatar } => (
+				Opcode::CMsgNewUser as u32,
+				ByteWriter::new(username.len() + avatar.len() + 2)
+					.write_string(username)
+					.write_string(avatar),
+			),
+			Self::SMsgClientId(id) => (
+				Opcode::SMsgClientId as u32,
+				ByteWriter::new(4).write_i32(*id),
+			),
+			Self::SMsgUserJoined {
+				broadcast_id,
+				unknown,
+				avatar,
+				username,
+			} => (
+				Opcode::SMsgUserJoined as u32,
+				ByteWriter::new(10 + avatar.len() + username.len())
+					.write_i32(*broadcast_id)
+					.write_i32(*unknown)
+					.write_string(avatar)
+					.write_string(username),
+			),
+			Self::SMsgUserLeft(id) => (
+				Opcode::SMsgUserLeft as u32,
+				ByteWriter::new(4).write_i32(*id),
+			),
+			Self::SMsgBroadcastId(id) => (
+				Opcode::SMsgBroadcastId as u32,
+				ByteWriter::new(4).write_i32(*id),
+			),
+			Self::MsgCommon(msg) => (Opcode::MsgCommon as u32, msg.encode()),
+			Self::CMsgStateChange(state) => (
+				Opcode::CMsgStateChange as u32,
+				ByteWriter::new(1).write_u8(*state),
+			),
+			Self::SMsgSetMaster(is_master) => (
+				Opcode::SMsgSetMaster as u32,
+				ByteWriter::new(1).write_u8(*is_master as u8),
+			),
+			Self::SMsgUserCount { unknown, count } => (
+				Opcode::SMsgUserCount as u32,
+				ByteWriter::new(5).write_u8(*unknown).write_i32(*count),
+			),
+			Self::Unknown { opcode, content } => {
+				(*opcode, ByteWriter::new(content.len()).write_arr(content))
+			}
+		}
+	}
+}
+
+impl CommonMessage {
+	/// Read a MsgCommon, consuming all of `reader`.
+	pub fn read(reader: &mut ByteReader) -> Result<Self, DecodeError> {
+		let broadcast_id = reader.read_i32()?;
+		let msg_type = reader.read_u32()?;
+		let strategy = reader.read_u8()?.into();
+
+		Ok(Self {
+			broadcast_id,
+			strategy,
+			content: Common::decode(msg_type, reader.read_rest())?,
+		})
+	}
+
+	pub fn encode(&self) -> ByteWriter {
+		let (msg_type, content) = self.content.encode();
+
+		ByteWriter::new(9 + content.bytes.len())
+			.write_i32(self.broadcast_id)
+			.write_u32(msg_type)
+			.write_u8(self.strategy.into())
+			.write_arr(&content.bytes)
+	}
+}
+
+impl Common {
+	pub fn decode(msg_type: u32, content: &[u8]) -> Result<Self, DecodeError> {
+		let mut reader = ByteReader::new(content);
+
+		let common = match msg_type {
+			2 => {
+				let mut rotation = Mat3::new();
+				for f in rotation.data.iter_mut() {
+					*f = reader.read_f32()?;
+				}
+
+				let position =
+					Vector3::new(reader.read_f32()?, reader.read_f32()?, reader.read_f32()?);
+
+				Self::TransformUpdate { rotation, position }
+			}
+			9 => Self::ChatSend(reader.read_string()?),
+			12 => Self::CharacterUpdate(reader.read_string()?),
+			13 => Self::NameChange(reader.read_string()?),
+			14 => Self::AvatarChange(reader.read_string()?),
+			15 => Self::PrivateChat {
+				broadcast_id: reader.read_i32()?,
+				message: reader.read_string()?,
+			},
+			16 => Self::VcRegister(reader.read_rest().to_vec()),
+			18 => Self::VoiceState(reader.read_rest().to_vec()),
+			19 => Self::Unknown19(reader.read_rest().to_vec()),
+			10000 => Self::ApplSpecific {
+				unknown: reader.read_u8()?,
+				method: reader.read_string()?,
+				strarg: reader.read_string()?,
+				intarg: reader.read_i32()?,
+			},
+			_ => Self::Unknown {
+				msg_type,
+				content: reader.read_rest().to_vec(),
+			},
+		};
+
+		Ok(common)
+	}
+
+	/// Encode the content of the message, returning it along with its type.
+	pub fn encode(&self) -> (u32, ByteWriter) {
+		match self {
+			Self::TransformUpdate { rotation, position } => {
+				let mut content = ByteWriter::new(48);
+				for f in rotation.data {
+					content = content.write_f32(f);
+				}
+
+				(
+					MsgCommon::TransformUpdate as u32,
+					content
+						.write_f32(position.x)
+						.write_f32(position.y)
+						.write_f32(position.z),
+				)
+			}
+			Self::ChatSend(text) => (MsgCommon::ChatSend as u32, string_content(text)),
+			Self::CharacterUpdate(data) => {
+				(MsgCommon::CharacterUpdate as u32, string_content(data))
+			}
+			Self::NameChange(name) => (MsgCommon::NameChange as u32, string_content(name)),
+			Self::AvatarChange(avatar) => (MsgCommon::AvatarChange as u32, string_content(avatar)),
+			Self::PrivateChat {
+				broadcast_id,
+				message,
+			} => (
+				MsgCommon::PrivateChat as u32,
+				ByteWriter::new(5 + message.len())
+					.write_i32(*broadcast_id)
+					.write_string(message),
+			),
+			Self::VcRegister(data) => (MsgCommon::VcRegister as u32, raw_content(data)),
+			Self::VoiceState(data) => (MsgCommon::VoiceState as u32, raw_content(data)),
+			Self::Unknown19(data) => (MsgCommon::Unknown19 as u32, raw_content(data)),
+			Self::ApplSpecific {
+				unknown,
+				method,
+				strarg,
+				intarg,
+			} => (
+				MsgCommon::ApplSpecific as u32,
+				ByteWriter::new(7 + method.len() + strarg.len())
+					.write_u8(*unknown)
+					.write_string(method)
+					.write_string(strarg)
+					.write_i32(*intarg),
+			),
+			Self::Unknown { msg_type, content } => (*msg_type, raw_content(content)),
+		}
+	}
+}
+
+fn string_content(s: &str) -> ByteWriter {
+	ByteWriter::new(s.len() + 1).write_string(s)
+}
+
+fn raw_content(data: &[u8]) -> ByteWriter {
+	ByteWriter::new(data.len()).write_arr(data)
+}
+
+impl PositionUpdate {
+	pub fn new(id: i32, position: Vector3) -> Self {
+		Self {
+			connection_id: id,
+			client_id: id,
+			broadcast_id: id,
+			position,
+			unknown: [1, 0],
+		}
+	}
+
+	/// Read a Position Update without its section type.
+	pub fn read(reader: &mut ByteReader) -> Result<Self, DecodeError> {
+		Ok(Self {
+			connection_id: reader.read_i32()?,
+			client_id: reader.read_i32()?,
+			broadcast_id: reader.read_i32()?,
+			position: Vector3::new(reader.read_f32()?, reader.read_f32()?, reader.read_f32()?),
+			unknown: reader.read_array()?,
+		})
+	}
+
+	pub fn encode(&self) -> ByteWriter {
+		ByteWriter::new(27)
+			.write_u8(2)
+			.write_i32(self.connection_id)
+			.write_i32(self.client_id)
+			.write_i32(self.broadcast_id)
+			.write_f32(self.position.x)
+			.write_f32(self.position.y)
+			.write_f32(self.position.z)
+			.write_arr(&self.unknown)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn general(opcode: u32, content: &[u8]) -> Vec<u8> {
+		ByteWriter::new(17 + content.len())
+			.write_u8(0)
+			.write_i32(5)
+			.write_i32(-9999)
+			.write_u32(opcode)
+			.write_u32(content.len() as u32)
+			.write_arr(content)
+			.bytes
+	}
+
+	fn common(msg_type: u32, strategy: u8, content: &[u8]) -> Vec<u8> {
+		let content = ByteWriter::new(9 + content.len())
+			.write_i32(7)
+			.write_u32(msg_type)
+			.write_u8(strategy)
+			.write_arr(content)
+			.bytes;
+
+		general(Opcode::MsgCommon as u32, &content)
+	}
+
+	fn floats(raw: &[i32]) -> Vec<u8> {
+		raw.iter().flat_map(|n| n.to_be_bytes()).collect()
+	}
+
+	fn assert_round_trip(bytes: &[u8]) {
+		let frame = Frame::decode(bytes).unwrap();
+		assert_eq!(frame.encode().bytes, bytes);
+	}
+
+	#[test]
+	fn frames() {
+		assert_round_trip(&[1, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]);
+
+		let mut update = vec![2];
+		update.extend(floats(&[3, 3, -3, 65535, -1, 16776960]));
+		update.extend([1, 0]);
+		assert_round_trip(&update);
+	}
+
+	#[test]
+	fn messages() {
+		assert_round_trip(&general(0, b"Someone\0avatar.wrl\0"));
+		assert_round_trip(&general(1, &floats(&[42])));
+		assert_round_trip(&general(
+			2,
+			&[floats(&[42, 1]), b"avatar.wrl\0Someone\0".to_vec()].concat(),
+		));
+		assert_round_trip(&general(3, &floats(&[42])));
+		assert_round_trip(&general(4, &floats(&[-42])));
+		assert_round_trip(&general(7, &[5]));
+		assert_round_trip(&general(8, &[0]));
+		assert_round_trip(&general(8, &[1]));
+		assert_round_trip(&general(11, &[1, 0, 0, 1, 0]));
+		assert_round_trip(&general(5, &[1, 2, 3]));
+		assert_round_trip(&general(12345, &[]));
+	}
+
+	#[test]
+	fn commons() {
+		let transform = floats(&[65535, 0, 0, 0, 65535, 0, 0, 0, -65535, 123456, -7, 16776960]);
+		assert_round_trip(&common(2, 0, &transform));
+		assert_round_trip(&common(9, 1, b"hello\0"));
+		assert_round_trip(&common(12, 2, b"sleep:0 1:000000000000:58:0:\0"));
+		assert_round_trip(&common(13, 3, b"Someone\0"));
+		assert_round_trip(&common(14, 4, b"avatar.wrl\0"));
+		assert_round_trip(&common(
+			15,
+			5,
+			&[floats(&[42]), b"psst\0".to_vec()].concat(),
+		));
+		assert_round_trip(&common(16, 6, &[0xde, 0xad, 0xbe, 0xef]));
+		assert_round_trip(&common(18, 0, &[1, 2]));
+		assert_round_trip(&common(19, 0, &[]));
+		assert_round_trip(&common(
+			10000,
+			2,
+			&[vec![2], b"method\0strarg\0".to_vec(), floats(&[-1])].concat(),
+		));
+		assert_round_trip(&common(77, 3, &[9, 9, 9]));
+	}
+
+	#[test]
+	fn trailing_bytes_are_rejected() {
+		let mut bytes = general(1, &[0, 0, 0, 42]);
+		bytes[16] = 5;
+		assert_eq!(
+			Frame::decode(&bytes).err(),
+			Some(DecodeError::SizeMismatch {
+				declared: 5,
+				actual: 4
+			})
+		);
+	}
+
+	#[test]
+	fn trailing_content_is_ignored() {
+		let canonical = |bytes: &[u8]| Frame::decode(bytes).unwrap().encode().bytes;
+
+		assert_eq!(
+			canonical(&general(1, &[0, 0, 0, 42, 0])),
+			general(1, &[0, 0, 0, 42])
+		);
+		assert_eq!(canonical(&common(9, 0, b"hi\0\0")), common(9, 0, b"hi\0"));
+		assert_eq!(canonical(&common(2, 0, &[0; 49])), common(2, 0, &[0; 48]));
+
+		let private = [floats(&[42]), b"psst\0".to_vec()].concat();
+		assert_eq!(
+			canonical(&common(15, 5, &[private.clone(), vec![1, 2, 3]].concat())),
+			common(15, 5, &private)
+		);
+
+		let appl = [vec![2], b"method\0strarg\0".to_vec(), floats(&[-1])].concat();
+		assert_eq!(
+			canonical(&common(10000, 2, &[appl.clone(), vec![0]].concat())),
+			common(10000, 2, &appl)
+		);
+	}
+
+	#[test]
+	fn unknown_set_master_flags_are_kept() {
+		for flag in [2, 255] {
+			assert_round_trip(&general(8, &[flag]));
+		}
+		assert_round_trip(&general(8, &[]));
+	}
+
+	#[test]
+	fn unknown_strategies_are_kept() {
+		for strategy in [7, 100, 255] {
+			assert_round_trip(&common(9, strategy, b"hi\0"));
+		}
+	}
+
+	#[test]
+	fn int32floats_up_to_256_are_exact() {
+		let max: i32 = 256 * 65535;
+		for raw in (-max..=max).step_by(97).chain([-max, -1, 0, 1, max]) {
+			let f = ByteReader::new(&raw.to_be_bytes()).read_f32().unwrap();
+			assert_eq!(
+				ByteWriter::new(4).write_f32(f).bytes,
+				raw.to_be_bytes(),
+				"{}",
+				raw
+			);
+		}
+	}
+}
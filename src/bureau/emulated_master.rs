@@ -0,0 +1,104 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{anyhow, Context};
+
+const LINE_FORMAT: &str = "<request method> <sender|all> <reply method> <intarg|sender> [strarg]";
+
+/// Who a reply of the emulated master is sent to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ReplyTarget {
+	/// Only the user that sent the request.
+	Sender,
+	/// Every user, including the one that sent the request.
+	All,
+}
+
+/// An ApplSpecific the Bureau sends in place of a master client.
+#[derive(Clone)]
+pub struct MasterReply {
+	pub target: ReplyTarget,
+	pub method: String,
+	pub strarg: String,
+	/// `None` uses the id of the user that sent the request.
+	pub intarg: Option<i32>,
+}
+
+/// Replies to the ApplSpecific requests that would otherwise go to the master client,
+/// keyed by the method of the request.
+///
+/// Read from files with one reply per line in the form of `LINE_FORMAT`, `#` starts a comment.
+/// The strarg is the rest of the line and may contain spaces.
+#[derive(Clone, Default)]
+pub struct EmulatedMaster {
+	replies: HashMap<String, Vec<MasterReply>>,
+}
+
+impl EmulatedMaster {
+	pub fn load(path: &Path) -> anyhow::Result<Self> {
+		let text = fs::read_to_string(path).with_context(|| format!("{:?}", path))?;
+		Self::parse(&text).with_context(|| format!("{:?}", path))
+	}
+
+	pub fn parse(text: &str) -> anyhow::Result<Self> {
+		let mut master = Self::default();
+
+		for (i, line) in text.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+
+			let (request, reply) = parse_line(line)
+				.ok_or_else(|| anyhow!("line {}: expected '{}'", i + 1, LINE_FORMAT))?;
+			master
+				.replies
+				.entry(request.to_string())
+				.or_default()
+				.push(reply);
+		}
+
+		Ok(master)
+	}
+
+	/// Get the replies to a request, if the emulated master knows how to answer it.
+	pub fn replies(&self, request: &str) -> Option<&[MasterReply]> {
+		self.replies.get(request).map(Vec::as_slice)
+	}
+}
+
+/// Split the first whitespace separated field off of `line`.
+fn field(line: &str) -> Option<(&str, &str)> {
+	let line = line.trim_start();
+	if line.is_empty() {
+		return None;
+	}
+
+	Some(line.split_once(char::is_whitespace).unwrap_or((line, "")))
+}
+
+fn parse_line(line: &str) -> Option<(&str, MasterReply)> {
+	let (request, rest) = field(line)?;
+	let (target, rest) = field(rest)?;
+	let (method, rest) = field(rest)?;
+	let (intarg, rest) = field(rest)?;
+
+	let target = match target {
+		"sender" => ReplyTarget::Sender,
+		"all" => ReplyTarget::All,
+		_ => return None,
+	};
+	let intarg = match intarg {
+		"sender" => None,
+		n => Some(n.parse().ok()?),
+	};
+
+	Some((
+		request,
+		MasterReply {
+			target,
+			method: method.to_string(),
+			strarg: rest.trim().to_string(),
+			intarg,
+		},
+	))
+}
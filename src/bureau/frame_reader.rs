@@ -0,0 +1,211 @@
+use super::protocol::{ByteReader, DecodeError};
+
+/// Largest General Message content that will be accepted.
+/// Would be a bad idea to dynamically allocate a number of bytes that could be u32::MAX.
+pub const MAX_CONTENT_SIZE: u32 = 1024;
+
+const GENERAL_MESSAGE_HEADER_SIZE: usize = 17;
+const SYS1_MESSAGE_SIZE: usize = 15;
+const POSITION_UPDATE_SIZE: usize = 27;
+
+enum FrameState {
+	/// Waiting for the section type byte.
+	Type,
+	/// Waiting for the rest of a General Message header.
+	GeneralHeader,
+	/// Waiting for a General Message with a known total size.
+	GeneralContent(usize),
+	/// Waiting for a frame with a fixed size.
+	Fixed(usize),
+}
+
+/// Splits a VSCP byte stream into complete Sys0/Sys1/Sys2 frames.
+/// Bytes can be pushed in any amount, frames are only yielded once every byte of them has arrived.
+pub struct FrameReader {
+	buf: Vec<u8>,
+	/// Start of the first frame that hasn't been yielded yet.
+	start: usize,
+	state: FrameState,
+}
+
+impl Default for FrameReader {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl FrameReader {
+	pub fn new() -> Self {
+		Self {
+			buf: Vec::new(),
+			start: 0,
+			state: FrameState::Type,
+		}
+	}
+
+	/// Append bytes read from the stream.
+	pub fn extend(&mut self, data: &[u8]) {
+		// Only move data to the front once most of the buffer has been consumed.
+		if self.start > 0 && self.start >= self.buf.len() / 2 {
+			self.buf.drain(..self.start);
+			self.start = 0;
+		}
+
+		self.buf.extend_from_slice(data);
+	}
+
+	/// Get the next complete frame, including its section type byte.
+	/// Once an error is returned the stream can't be resynchronised and should be dropped.
+	pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, DecodeError> {
+		loop {
+			let pending = &self.buf[self.start..];
+
+			match self.state {
+				FrameState::Type => {
+					let Some(section_type) = pending.first() else {
+						return Ok(None);
+					};
+
+					self.state = match section_type {
+						0 => FrameState::GeneralHeader,
+						1 => FrameState::Fixed(SYS1_MESSAGE_SIZE),
+						2 => FrameState::Fixed(POSITION_UPDATE_SIZE),
+						_ => return Err(DecodeError::UnknownSectionType(*section_type)),
+					};
+				}
+				FrameState::GeneralHeader => {
+					if pending.len() < GENERAL_MESSAGE_HEADER_SIZE {
+						return Ok(None);
+					}
+
+					let mut header = ByteReader::new(&pending[13..GENERAL_MESSAGE_HEADER_SIZE]);
+					let size = header.read_u32()?;
+					if size > MAX_CONTENT_SIZE {
+						return Err(DecodeError::TooLarge(size));
+					}
+
+					self.state =
+						FrameState::GeneralContent(GENERAL_MESSAGE_HEADER_SIZE + size as usize);
+				}
+				FrameState::GeneralContent(size) | FrameState::Fixed(size) => {
+					if pending.len() < size {
+						return Ok(None);
+					}
+
+					let start = self.start;
+					self.start += size;
+					self.state = FrameState::Type;
+
+					return Ok(Some(self.buf[start..start + size].to_vec()));
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::bureau::{
+		codec::{Common, GeneralMessage, PositionUpdate},
+		math::Vector3,
+		protocol::Strategy,
+	};
+
+	/// One frame of each section type.
+	fn frames() -> Vec<Vec<u8>> {
+		let chat = Common::ChatSend("Bot: hello".to_string());
+		let mut sys1 = vec![1];
+		sys1.extend(1..SYS1_MESSAGE_SIZE as u8);
+
+		vec![
+			GeneralMessage::common(1, 1, Strategy::AuraClients, chat)
+				.encode()
+				.bytes,
+			sys1,
+			PositionUpdate::new(1, Vector3::new(1.0, 2.0, 3.0))
+				.encode()
+				.bytes,
+		]
+	}
+
+	fn read_all(reader: &mut FrameReader) -> Vec<Vec<u8>> {
+		let mut frames = Vec::new();
+		while let Some(frame) = reader.next_frame().unwrap() {
+			frames.push(frame);
+		}
+		frames
+	}
+
+	#[test]
+	fn split_at_every_offset() {
+		for frame in frames() {
+			for split in 0..=frame.len() {
+				let mut reader = FrameReader::new();
+
+				reader.extend(&frame[..split]);
+				let mut read = read_all(&mut reader);
+				if split < frame.len() {
+					assert!(read.is_empty(), "frame yielded after {} bytes", split);
+				}
+
+				reader.extend(&frame[split..]);
+				read.extend(read_all(&mut reader));
+				assert_eq!(read, std::slice::from_ref(&frame));
+			}
+		}
+	}
+
+	#[test]
+	fn coalesced_frames() {
+		let frames = frames();
+		let mut reader = FrameReader::new();
+
+		reader.extend(&frames.concat());
+		reader.extend(&frames.concat());
+		assert_eq!(read_all(&mut reader), [frames.clone(), frames].concat());
+	}
+
+	#[test]
+	fn invalid_frames() {
+		// Only General Messages declare their size, the others have a fixed one.
+		let mut frame = frames().swap_remove(0);
+		frame[13..GENERAL_MESSAGE_HEADER_SIZE]
+			.copy_from_slice(&(MAX_CONTENT_SIZE + 1).to_be_bytes());
+		let mut reader = FrameReader::new();
+		reader.extend(&frame[..GENERAL_MESSAGE_HEADER_SIZE]);
+		assert_eq!(
+			reader.next_frame(),
+			Err(DecodeError::TooLarge(MAX_CONTENT_SIZE + 1))
+		);
+
+		let mut reader = FrameReader::new();
+		reader.extend(&[3]);
+		assert_eq!(reader.next_frame(), Err(DecodeError::UnknownSectionType(3)));
+	}
+
+	#[test]
+	fn compaction() {
+		let frames = frames();
+		let stream = frames.concat();
+		let partial = frames[0].len() + 5;
+		let mut reader = FrameReader::new();
+
+		// A partial frame stays in the buffer after the complete one before it.
+		reader.extend(&stream[..partial]);
+		assert_eq!(read_all(&mut reader), [frames[0].clone()]);
+		assert_eq!(reader.start, frames[0].len());
+		assert_eq!(reader.buf.len(), partial);
+
+		// More than half of the buffer was consumed, so it's moved to the front before growing.
+		reader.extend(&stream[partial..]);
+		assert_eq!(reader.start, 0);
+		assert_eq!(reader.buf, stream[frames[0].len()..]);
+		assert_eq!(read_all(&mut reader), frames[1..]);
+
+		// Once it's all consumed, nothing is left of the old frames.
+		reader.extend(&frames[2]);
+		assert_eq!(reader.buf, frames[2]);
+		assert_eq!(read_all(&mut reader), [frames[2].clone()]);
+	}
+}
@@ -0,0 +1,339 @@
+use std::{collections::BTreeMap, fmt};
+
+/// Deepest arrays and objects may be nested, which also catches tables that contain themselves.
+pub const MAX_DEPTH: usize = 64;
+
+/// A JSON value, numbers are kept as `f64` like in Lua.
+#[derive(Clone, PartialEq)]
+pub enum Json {
+	Null,
+	Bool(bool),
+	Number(f64),
+	String(String),
+	Array(Vec<Json>),
+	Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+	pub fn parse(text: &str) -> Option<Self> {
+		let mut parser = Parser {
+			bytes: text.as_bytes(),
+			pos: 0,
+		};
+
+		let value = parser.value(0)?;
+		parser.skip_whitespace();
+
+		(parser.pos == parser.bytes.len()).then_some(value)
+	}
+}
+
+struct Parser<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+}
+
+impl Parser<'_> {
+	fn skip_whitespace(&mut self) {
+		while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+			self.pos += 1;
+		}
+	}
+
+	fn next(&mut self) -> Option<u8> {
+		let b = *self.bytes.get(self.pos)?;
+		self.pos += 1;
+		Some(b)
+	}
+
+	fn expect(&mut self, text: &str) -> Option<()> {
+		let end = self.pos + text.len();
+		(self.bytes.get(self.pos..end)? == text.as_bytes()).then(|| self.pos = end)
+	}
+
+	fn value(&mut self, depth: usize) -> Option<Json> {
+		self.skip_whitespace();
+		let b = *self.bytes.get(self.pos)?;
+		if matches!(b, b'[' | b'{') && depth >= MAX_DEPTH {
+			return None;
+		}
+
+		match b {
+			b'n' => self.expect("null").map(|_| Json::Null),
+			b't' => self.expect("true").map(|_| Json::Bool(true)),
+			b'f' => self.expect("false").map(|_| Json::Bool(false)),
+			b'"' => self.string().map(Json::String),
+			b'[' => {
+				self.pos += 1;
+				let mut values = Vec::new();
+
+				self.skip_whitespace();
+				if self.bytes.get(self.pos) == Some(&b']') {
+					self.pos += 1;
+					return Some(Json::Array(values));
+				}
+
+				loop {
+					values.push(self.value(depth + 1)?);
+
+					self.skip_whitespace();
+					match self.next()? {
+						b',' => (),
+						b']' => return Some(Json::Array(values)),
+						_ => return None,
+					}
+				}
+			}
+			b'{' => {
+				self.pos += 1;
+				let mut values = BTreeMap::new();
+
+				self.skip_whitespace();
+				if self.bytes.get(self.pos) == Some(&b'}') {
+					self.pos += 1;
+					return Some(Json::Object(values));
+				}
+
+				loop {
+					self.skip_whitespace();
+					let key = self.string()?;
+					self.skip_whitespace();
+					self.expect(":")?;
+					values.insert(key, self.value(depth + 1)?);
+
+					self.skip_whitespace();
+					match self.next()? {
+						b',' => (),
+						b'}' => return Some(Json::Object(values)),
+						_ => return None,
+					}
+				}
+			}
+			_ => self.number().map(Json::Number),
+		}
+	}
+
+	/// Skip over digits, returning how many there were.
+	fn digits(&mut self) -> usize {
+		let start = self.pos;
+		while let Some(b'0'..=b'9') = self.bytes.get(self.pos) {
+			self.pos += 1;
+		}
+
+		self.pos - start
+	}
+
+	fn number(&mut self) -> Option<f64> {
+		let start = self.pos;
+
+		if self.bytes.get(self.pos) == Some(&b'-') {
+			self.pos += 1;
+		}
+		let leading_zero = self.bytes.get(self.pos) == Some(&b'0');
+		match self.digits() {
+			0 => return None,
+			n if n > 1 && leading_zero => return None,
+			_ => (),
+		}
+		if self.bytes.get(self.pos) == Some(&b'.') {
+			self.pos += 1;
+			if self.digits() == 0 {
+				return None;
+			}
+		}
+		if let Some(b'e' | b'E') = self.bytes.get(self.pos) {
+			self.pos += 1;
+			if let Some(b'+' | b'-') = self.bytes.get(self.pos) {
+				self.pos += 1;
+			}
+			if self.digits() == 0 {
+				return None;
+			}
+		}
+
+		let number = std::str::from_utf8(&self.bytes[start..self.pos])
+			.ok()?
+			.parse::<f64>()
+			.ok()?;
+		number.is_finite().then_some(number)
+	}
+
+	fn hex4(&mut self) -> Option<u32> {
+		let digits = self.bytes.get(self.pos..self.pos + 4)?;
+		self.pos += 4;
+
+		digits
+			.iter()
+			.try_fold(0, |code, b| Some(code << 4 | (*b as char).to_digit(16)?))
+	}
+
+	fn string(&mut self) -> Option<String> {
+		self.expect("\"")?;
+		let mut bytes = Vec::new();
+
+		loop {
+			match self.next()? {
+				b'"' => return String::from_utf8(bytes).ok(),
+				b'\\' => {
+					let c = match self.next()? {
+						b'"' => '"',
+						b'\\' => '\\',
+						b'/' => '/',
+						b'b' => '\u{8}',
+						b'f' => '\u{c}',
+						b'n' => '\n',
+						b'r' => '\r',
+						b't' => '\t',
+						b'u' => {
+							let mut code = self.hex4()?;
+							// Characters outside of the BMP are written as a surrogate pair.
+							if (0xdc00..0xe000).contains(&code) {
+								return None;
+							}
+							if (0xd800..0xdc00).contains(&code) {
+								self.expect("\\u")?;
+								let low = self.hex4()?;
+								if !(0xdc00..0xe000).contains(&low) {
+									return None;
+								}
+								code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+							}
+
+							char::from_u32(code)?
+						}
+						_ => return None,
+					};
+
+					bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+				}
+				b if b < 0x20 => return None,
+				b => bytes.push(b),
+			}
+		}
+	}
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+	f.write_str("\"")?;
+
+	for c in s.chars() {
+		match c {
+			'"' => f.write_str("\\\"")?,
+			'\\' => f.write_str("\\\\")?,
+			'\n' => f.write_str("\\n")?,
+			'\r' => f.write_str("\\r")?,
+			'\t' => f.write_str("\\t")?,
+			c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+			c => write!(f, "{}", c)?,
+		}
+	}
+
+	f.write_str("\"")
+}
+
+impl fmt::Display for Json {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Json::Null => f.write_str("null"),
+			Json::Bool(b) => write!(f, "{}", b),
+			Json::Number(n) => write!(f, "{}", n),
+			Json::String(s) => write_string(f, s),
+			Json::Array(values) => {
+				f.write_str("[")?;
+				for (i, value) in values.iter().enumerate() {
+					if i > 0 {
+						f.write_str(",")?;
+					}
+					write!(f, "{}", value)?;
+				}
+				f.write_str("]")
+			}
+			Json::Object(values) => {
+				f.write_str("{")?;
+				for (i, (key, value)) in values.iter().enumerate() {
+					if i > 0 {
+						f.write_str(",")?;
+					}
+					write_string(f, key)?;
+					write!(f, ":{}", value)?;
+				}
+				f.write_str("}")
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn round_trip(text: &str) -> String {
+		Json::parse(text).unwrap().to_string()
+	}
+
+	#[test]
+	fn values() {
+		assert_eq!(round_trip(" null "), "null");
+		assert_eq!(round_trip("[true,false]"), "[true,false]");
+		assert_eq!(
+			round_trip("[0,-1,1.5,2e3,-0.25E-2]"),
+			"[0,-1,1.5,2000,-0.0025]"
+		);
+		assert_eq!(
+			round_trip(r#"{"b": [1, {}], "a": "x"}"#),
+			r#"{"a":"x","b":[1,{}]}"#
+		);
+		assert_eq!(round_trip("[1,null,3]"), "[1,null,3]");
+	}
+
+	#[test]
+	fn invalid_numbers() {
+		for text in [
+			"+1", "01", "1.", ".5", "1e", "-", "1e999", "-1e999", "NaN", "inf",
+		] {
+			assert!(Json::parse(text).is_none(), "{}", text);
+		}
+	}
+
+	#[test]
+	fn escapes() {
+		let s = Json::parse(r#""\"\\\/\b\f\n\r\t\u00e9\u0001""#).unwrap();
+		assert!(s == Json::String("\"\\/\u{8}\u{c}\n\r\t\u{e9}\u{1}".to_string()));
+		assert!(Json::parse(&s.to_string()).unwrap() == s);
+
+		for text in [
+			r#""\x""#,
+			r#""\u+abc""#,
+			r#""\u12g4""#,
+			r#""\u12""#,
+			"\"\u{1}\"",
+			r#""a"#,
+		] {
+			assert!(Json::parse(text).is_none(), "{}", text);
+		}
+	}
+
+	#[test]
+	fn surrogates() {
+		let s = Json::parse(r#""\ud83d\ude00""#).unwrap();
+		assert!(s == Json::String("\u{1f600}".to_string()));
+
+		for text in [
+			r#""\ud83d""#,
+			r#""\ud83da""#,
+			r#""\ude00""#,
+			r#""\ude00\ud83d""#,
+			r#""\ud83d\u0041""#,
+		] {
+			assert!(Json::parse(text).is_none(), "{}", text);
+		}
+	}
+
+	#[test]
+	fn depth_limit() {
+		let nested = |n| "[".repeat(n) + &"]".repeat(n);
+
+		assert!(Json::parse(&nested(MAX_DEPTH)).is_some());
+		assert!(Json::parse(&nested(MAX_DEPTH + 1)).is_none());
+	}
+}
@@ -0,0 +1,36 @@
+local ftbl = ...
+
+local send_appl_specific = ftbl.send_appl_specific
+local hook_stats = ftbl.hook_stats
+local reload_plugins = ftbl.reload_plugins
+
+---@diagnostic disable-next-line: lowercase-global
+bureau = {}
+
+--- Send an ApplSpecific from the bureau itself.
+--- Aura strategies are relative to the user with the `target` id, use -9999 as `target` when sending to every user.
+---@param strategy number
+---@param target number
+---@param method string
+---@param strarg string?
+---@param intarg number?
+function bureau.sendApplSpecific(strategy, target, method, strarg, intarg)
+	send_appl_specific(strategy, target, method, strarg or "", intarg or 0)
+end
+
+---@class HookStats
+---@field calls number
+---@field total number Milliseconds spent in all calls.
+---@field max number Milliseconds spent in the longest call.
+---@field overruns number Calls aborted for running past the time budget.
+
+--- Get how long the hooks of every event have taken so far, keyed by event.
+---@return table<string, HookStats>
+function bureau.getHookStats()
+	return hook_stats()
+end
+
+--- Remove every hook and load all plugins again once the current hook is done.
+function bureau.reloadPlugins()
+	reload_plugins()
+end
@@ -1,116 +1,325 @@
-local users, user_meta = ...
+local users, user_meta, master_changed, plugin_of, call_hook = ...
 
 ---@diagnostic disable-next-line: lowercase-global
 hook = {}
 
-local function ins_tbl_ret(tbl, obj)
-	local pos = #tbl + 1
-	tbl[pos] = obj
-	return obj
+-- Plugins that have been disabled for running too long.
+local disabled = {}
+
+-- Every kind of hook, so they can all be cleared when reloading.
+local all_hooks = {}
+
+-- Kind of hook that every handle belongs to.
+local handles = {}
+local next_handle = 1
+
+local function new_hooks()
+	-- The list is replaced rather than changed, so hooks can be added or removed while it's being ran.
+	local hooks = { list = {} }
+	all_hooks[#all_hooks + 1] = hooks
+	return hooks
 end
 
-local think_hooks = {}
+--- Add a hook that runs before every hook with a lower priority, and after the ones added before it otherwise.
+local function add_hook(hooks, fn, priority)
+	if type(fn) ~= "function" then
+		error("hook must be a function", 3)
+	end
+	priority = priority or 0
+
+	local handle = next_handle
+	next_handle = next_handle + 1
+
+	local entry = { fn = fn, priority = priority, handle = handle, owner = plugin_of(fn) }
+	local list = {}
+	local added = false
+
+	for _, other in ipairs(hooks.list) do
+		if not added and priority > other.priority then
+			list[#list + 1] = entry
+			added = true
+		end
+		list[#list + 1] = other
+	end
+	if not added then list[#list + 1] = entry end
+
+	hooks.list = list
+	handles[handle] = hooks
+
+	return handle
+end
+
+--- Remove a hook by the handle that adding it returned.
+---@param handle integer
+---@return boolean removed
+function hook.remove(handle)
+	local hooks = handles[handle]
+	if not hooks then return false end
+
+	local list = {}
+	for _, entry in ipairs(hooks.list) do
+		if entry.handle ~= handle then list[#list + 1] = entry end
+	end
+
+	hooks.list = list
+	handles[handle] = nil
+
+	return true
+end
+
+local think_hooks = new_hooks()
 ---@param fn fun()
----@return integer
-function hook.onThink(fn)
-	return ins_tbl_ret(think_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onThink(fn, priority)
+	return add_hook(think_hooks, fn, priority)
 end
 
-local user_connect_hooks = {}
+local user_connect_hooks = new_hooks()
 ---@param fn fun(addr: string):boolean?
----@return integer
-function hook.onUserConnect(fn)
-	return ins_tbl_ret(user_connect_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onUserConnect(fn, priority)
+	return add_hook(user_connect_hooks, fn, priority)
 end
 
-local new_user_hooks = {}
----@param fn fun(user: User, name: string, avatar: string)
----@return integer
-function hook.onNewUser(fn)
-	return ins_tbl_ret(new_user_hooks, fn)
+local new_user_hooks = new_hooks()
+---@param fn fun(user: User, name: string, avatar: string, reloaded: boolean)
+---@param priority integer?
+---@return integer handle
+function hook.onNewUser(fn, priority)
+	return add_hook(new_user_hooks, fn, priority)
 end
 
-local pos_update_hooks = {}
+local pos_update_hooks = new_hooks()
 ---@param fn fun(user: User, pos: Vector)
----@return integer
-function hook.onPositionUpdate(fn)
-	return ins_tbl_ret(pos_update_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onPositionUpdate(fn, priority)
+	return add_hook(pos_update_hooks, fn, priority)
 end
 
-local trans_update_hooks = {}
+local trans_update_hooks = new_hooks()
 ---@param fn fun(user: User)
----@return integer
-function hook.onTransformUpdate(fn)
-	return ins_tbl_ret(trans_update_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onTransformUpdate(fn, priority)
+	return add_hook(trans_update_hooks, fn, priority)
 end
 
-local chat_send_hooks = {}
+local chat_send_hooks = new_hooks()
 ---@param fn fun(user: User, msg: string):string?
----@return integer
-function hook.onChatSend(fn)
-	return ins_tbl_ret(chat_send_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onChatSend(fn, priority)
+	return add_hook(chat_send_hooks, fn, priority)
 end
 
-local name_change_hooks = {}
+local name_change_hooks = new_hooks()
 ---@param fn fun(user: User, name: string, old: string)
----@return integer
-function hook.onNameChange(fn)
-	return ins_tbl_ret(name_change_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onNameChange(fn, priority)
+	return add_hook(name_change_hooks, fn, priority)
 end
 
-local avatar_change_hooks = {}
+local avatar_change_hooks = new_hooks()
 ---@param fn fun(user: User, avatar: string, old: string)
----@return integer
-function hook.onAvatarChange(fn)
-	return ins_tbl_ret(avatar_change_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onAvatarChange(fn, priority)
+	return add_hook(avatar_change_hooks, fn, priority)
 end
 
-local private_chat_hooks = {}
+local private_chat_hooks = new_hooks()
 ---@param fn fun(sender: User, receiver: User, msg: string):string?
----@return integer
-function hook.onPrivateChat(fn)
-	return ins_tbl_ret(private_chat_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onPrivateChat(fn, priority)
+	return add_hook(private_chat_hooks, fn, priority)
 end
 
-local aura_enter_hooks = {}
+local aura_enter_hooks = new_hooks()
 ---@param fn fun(u1: User, u2: User)
----@return integer
-function hook.onAuraEnter(fn)
-	return ins_tbl_ret(aura_enter_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onAuraEnter(fn, priority)
+	return add_hook(aura_enter_hooks, fn, priority)
 end
 
-local aura_leave_hooks = {}
+local aura_leave_hooks = new_hooks()
 ---@param fn fun(u1: User, u2: User)
----@return integer
-function hook.onAuraLeave(fn)
-	return ins_tbl_ret(aura_leave_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onAuraLeave(fn, priority)
+	return add_hook(aura_leave_hooks, fn, priority)
 end
 
-local user_disconnect_hooks = {}
+local user_disconnect_hooks = new_hooks()
 ---@param fn fun(user: User)
----@return integer
-function hook.onUserDisconnect(fn)
-	return ins_tbl_ret(user_disconnect_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onUserDisconnect(fn, priority)
+	return add_hook(user_disconnect_hooks, fn, priority)
+end
+
+local appl_specific_hooks = new_hooks()
+---@param fn fun(sender: User, strategy: number, target: number, method: string, strarg: string, intarg: number):(string|boolean)?, string?, number?
+---@param priority integer?
+---@return integer handle
+function hook.onApplSpecific(fn, priority)
+	return add_hook(appl_specific_hooks, fn, priority)
 end
 
-local plugins_loaded_hooks = {}
+local vc_register_hooks = new_hooks()
+---@param fn fun(user: User):boolean?
+---@param priority integer?
+---@return integer handle
+function hook.onVcRegister(fn, priority)
+	return add_hook(vc_register_hooks, fn, priority)
+end
+
+local voice_state_hooks = new_hooks()
+---@param fn fun(user: User):boolean?
+---@param priority integer?
+---@return integer handle
+function hook.onVoiceState(fn, priority)
+	return add_hook(voice_state_hooks, fn, priority)
+end
+
+---@class Character
+---@field sleep boolean
+---@field avatar number
+---@field parts number[][] Colour and scale of every body part.
+---@field minutes number
+---@field medal number
+
+local character_update_hooks = new_hooks()
+---@param fn fun(user: User, character: Character):Character|false|nil
+---@param priority integer?
+---@return integer handle
+function hook.onCharacterUpdate(fn, priority)
+	return add_hook(character_update_hooks, fn, priority)
+end
+
+local state_change_hooks = new_hooks()
+---@param fn fun(user: User, new: string, old: string)
+---@param priority integer?
+---@return integer handle
+function hook.onStateChange(fn, priority)
+	return add_hook(state_change_hooks, fn, priority)
+end
+
+local master_elect_hooks = new_hooks()
+---@param fn fun(candidates: User[]):User?
+---@param priority integer?
+---@return integer handle
+function hook.onMasterElect(fn, priority)
+	return add_hook(master_elect_hooks, fn, priority)
+end
+
+local master_change_hooks = new_hooks()
+---@param fn fun(new: User?, old: User?)
+---@param priority integer?
+---@return integer handle
+function hook.onMasterChange(fn, priority)
+	return add_hook(master_change_hooks, fn, priority)
+end
+
+---@class MasterReply
+---@field to "sender"|"all"?
+---@field method string
+---@field strarg string?
+---@field intarg number?
+
+local master_request_hooks = new_hooks()
+---@param fn fun(user: User, method: string, strarg: string, intarg: number):MasterReply[]?
+---@param priority integer?
+---@return integer handle
+function hook.onMasterRequest(fn, priority)
+	return add_hook(master_request_hooks, fn, priority)
+end
+
+local plugins_loaded_hooks = new_hooks()
 ---@param fn fun()
----@return integer
-function hook.onPluginsLoaded(fn)
-	return ins_tbl_ret(plugins_loaded_hooks, fn)
+---@param priority integer?
+---@return integer handle
+function hook.onPluginsLoaded(fn, priority)
+	return add_hook(plugins_loaded_hooks, fn, priority)
+end
+
+local reload_hooks = new_hooks()
+---@param fn fun()
+---@param priority integer?
+---@return integer handle
+function hook.onReload(fn, priority)
+	return add_hook(reload_hooks, fn, priority)
+end
+
+--- Like table.pack, keeps nils in between return values.
+local function pack(...)
+	return { n = select("#", ...), ... }
 end
 
-local function run_hooks(tbl, ...)
-	for i = 1, #tbl do
-		local fn = tbl[i]
-		local ret = { fn(...) }
+--- Run hooks in order until one of them returns something other than nil, and return that.
+--- A hook that errors is logged and skipped, unless it ran past the time budget which aborts the whole event.
+local function run_hooks(hooks, ...)
+	local list = hooks.list
 
-		if ret then
-			return unpack(ret)
+	for i = 1, #list do
+		local entry = list[i]
+
+		if not (entry.owner and disabled[entry.owner]) then
+			local ret = pack(call_hook(entry.owner, entry.fn, ...))
+
+			if ret[1] then
+				for j = 2, ret.n do
+					if ret[j] ~= nil then
+						return unpack(ret, 2, ret.n)
+					end
+				end
+			end
 		end
 	end
 end
 
+-- Hooks of events that plugins run themselves, by event name.
+local custom_hooks = {}
+
+--- Add a hook to an event that plugins run with `hook.run`.
+---@param name string
+---@param fn fun(...):...
+---@param priority integer?
+---@return integer handle
+function hook.add(name, fn, priority)
+	if type(name) ~= "string" then
+		error("event name must be a string", 2)
+	end
+
+	local hooks = custom_hooks[name]
+	if not hooks then
+		hooks = new_hooks()
+		custom_hooks[name] = hooks
+	end
+
+	return add_hook(hooks, fn, priority)
+end
+
+--- Run the hooks added to an event with `hook.add`, returning what the first one to return something returned.
+--- Like for other events, a hook that errors is logged and skipped.
+---@param name string
+---@return ...
+function hook.run(name, ...)
+	if type(name) ~= "string" then
+		error("event name must be a string", 2)
+	end
+
+	local hooks = custom_hooks[name]
+	if hooks then
+		return run_hooks(hooks, ...)
+	end
+end
+
 return {
 	think = function()
 		return run_hooks(think_hooks)
@@ -124,12 +333,13 @@ return {
 			name = name,
 			avatar = avatar,
 			ip = ip,
+			state = "Active",
 			_pos = Vector(0, 0, 0),
 			_rot = Basis(),
 		}, user_meta)
 		users[id] = u
 
-		return run_hooks(new_user_hooks, u, name, avatar)
+		return run_hooks(new_user_hooks, u, name, avatar, false)
 	end,
 	pos_update = function(id, x, y, z)
 		local user = users[id]
@@ -187,8 +397,84 @@ return {
 
 		return run_hooks(user_disconnect_hooks, u)
 	end,
+	appl_specific = function(id, strategy, target, method, strarg, intarg)
+		local method2, strarg2, intarg2 = run_hooks(appl_specific_hooks, users[id], strategy, target, method, strarg, intarg)
+		if method2 == false then return false end
+		-- Returning true only allows it.
+		if method2 == true then return true end
+
+		return true, method2, strarg2, intarg2
+	end,
+	vc_register = function(id)
+		return run_hooks(vc_register_hooks, users[id])
+	end,
+	voice_state = function(id)
+		return run_hooks(voice_state_hooks, users[id])
+	end,
+	character_update = function(id, character)
+		local u = users[id]
+		if not u then return true end
+
+		local ret = run_hooks(character_update_hooks, u, character)
+		if ret == false then return false end
+
+		u._character = ret or character
+
+		return true, ret
+	end,
+	state_change = function(id, state, old)
+		local u = users[id]
+		if not u then return end
+
+		u.state = state
+
+		return run_hooks(state_change_hooks, u, state, old)
+	end,
+	master_elect = function(ids)
+		local candidates = {}
+		for i = 1, #ids do
+			candidates[i] = users[ids[i]]
+		end
+
+		local u = run_hooks(master_elect_hooks, candidates)
+		return u and u.id
+	end,
+	master_change = function(new, old)
+		master_changed(new)
+
+		return run_hooks(master_change_hooks, users[new], users[old])
+	end,
+	master_request = function(id, method, strarg, intarg)
+		return run_hooks(master_request_hooks, users[id], method, strarg, intarg)
+	end,
 	plugins_loaded = function()
 		return run_hooks(plugins_loaded_hooks)
+	end,
+	disable_plugin = function(name)
+		disabled[name] = true
+	end,
+	is_disabled = function(name)
+		return disabled[name] == true
+	end,
+	--- Remove every hook before the plugins are loaded again.
+	reset = function()
+		for i = 1, #all_hooks do
+			all_hooks[i].list = {}
+		end
+
+		handles = {}
+		disabled = {}
+	end,
+	reload = function(ids)
+		run_hooks(plugins_loaded_hooks)
+		run_hooks(reload_hooks)
+
+		for i = 1, #ids do
+			local u = users[ids[i]]
+			if u then
+				run_hooks(new_user_hooks, u, u.name, u.avatar, true)
+			end
+		end
 	end
 }
 
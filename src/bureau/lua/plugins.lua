@@ -0,0 +1,14 @@
+local ftbl = ...
+
+local plugin_export = ftbl.plugin_export
+
+---@diagnostic disable-next-line: lowercase-global
+plugins = {}
+
+--- Get what another plugin's init.lua returned, by the name of its directory.
+--- List it under `dependencies` in plugin.toml to make sure it's loaded first.
+---@param name string
+---@return any
+function plugins.get(name)
+	return plugin_export(name)
+end
@@ -0,0 +1,124 @@
+local ftbl = ...
+
+local path_in = ftbl.path_in
+
+-- Metatables every plugin's values share, changing them would change those of every other plugin.
+local shared = {
+	[getmetatable("")] = true,
+	[getmetatable(Vector())] = true,
+	[getmetatable(Basis())] = true,
+	[ftbl.user_meta] = true,
+}
+
+local base = {
+	"_VERSION", "assert", "error", "ipairs", "next", "pairs", "pcall", "print", "rawequal", "rawget",
+	"rawset", "select", "tonumber", "tostring", "type", "unpack", "xpcall",
+	"Vector", "Basis",
+}
+
+-- Copied for every plugin so that changing them doesn't affect other plugins.
+local libs = {
+	"string", "table", "math", "coroutine", "bit",
+	"hook", "bureau", "user_manager", "timer", "storage", "plugins",
+}
+
+local os_funcs = { "clock", "date", "difftime", "time" }
+
+local gc_options = { collect = true, count = true, step = true }
+
+local function copy(tbl, keys)
+	local ret = {}
+
+	if keys then
+		for _, k in ipairs(keys) do
+			ret[k] = tbl[k]
+		end
+	else
+		for k, v in pairs(tbl) do
+			ret[k] = v
+		end
+	end
+
+	return ret
+end
+
+--- Create the environment of a plugin that may only access files in `dir`.
+---@param dir string
+---@return table
+return function(dir)
+	local env = copy(_G, base)
+	for _, name in ipairs(libs) do
+		env[name] = copy(_G[name])
+	end
+	env._G = env
+
+	local function resolve(path)
+		local resolved = type(path) == "string" and path_in(dir, path)
+		if not resolved then
+			error("access to '" .. tostring(path) .. "' is not allowed", 3)
+		end
+
+		return resolved
+	end
+
+	function env.getmetatable(v)
+		local meta = getmetatable(v)
+		if shared[meta] then return nil end
+
+		return meta
+	end
+
+	function env.setmetatable(tbl, meta)
+		if shared[getmetatable(tbl)] then
+			error("changing a shared metatable is not allowed", 2)
+		end
+
+		return setmetatable(tbl, meta)
+	end
+
+	function env.collectgarbage(opt, arg)
+		if opt ~= nil and not gc_options[opt] then
+			error("collectgarbage option '" .. tostring(opt) .. "' is not allowed", 2)
+		end
+
+		return collectgarbage(opt, arg)
+	end
+
+	-- Only text chunks are loaded, bytecode can be crafted to break out of the sandbox.
+	-- Plugins are told apart by chunk name, so the name is always kept inside of the plugin's directory.
+	function env.load(chunk, name, _, chunk_env)
+		return load(chunk, "=" .. dir .. "/" .. tostring(name or "load"), "t", chunk_env or env)
+	end
+	env.loadstring = env.load
+
+	function env.loadfile(path)
+		return loadfile(resolve(path), "t", env)
+	end
+
+	function env.dofile(path)
+		return assert(loadfile(resolve(path), "t", env))()
+	end
+
+	local modules = {}
+	function env.require(name)
+		if modules[name] == nil then
+			local path = resolve(tostring(name):gsub("%.", "/") .. ".lua")
+			modules[name] = assert(loadfile(path, "t", env))(name)
+			if modules[name] == nil then modules[name] = true end
+		end
+
+		return modules[name]
+	end
+
+	env.io = {
+		open = function(path, mode) return io.open(resolve(path), mode) end,
+		lines = function(path, ...) return io.lines(resolve(path), ...) end,
+		write = function(...) return io.write(...) end,
+		type = io.type,
+		stdout = io.stdout,
+		stderr = io.stderr,
+	}
+	env.os = copy(os, os_funcs)
+
+	return env
+end
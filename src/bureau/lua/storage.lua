@@ -0,0 +1,33 @@
+local ftbl = ...
+
+local storage_get = ftbl.storage_get
+local storage_set = ftbl.storage_set
+local storage_keys = ftbl.storage_keys
+
+---@diagnostic disable-next-line: lowercase-global
+storage = {}
+
+--- Get a value the calling plugin stored.
+---@param key string
+---@return any
+function storage.get(key)
+	return storage_get(key)
+end
+
+--- Store a value for the calling plugin, tables are copied and may not contain functions or userdata.
+---@param key string
+---@param value any
+function storage.set(key, value)
+	storage_set(key, value)
+end
+
+---@param key string
+function storage.delete(key)
+	storage_set(key, nil)
+end
+
+--- Get every key the calling plugin has stored something under.
+---@return string[]
+function storage.keys()
+	return storage_keys()
+end
@@ -0,0 +1,110 @@
+local ftbl, plugin_of, is_disabled, call_hook = ...
+
+local schedule_timer = ftbl.schedule_timer
+
+---@diagnostic disable-next-line: lowercase-global
+timer = {}
+
+-- Pending timers keyed by their id, and the ids of named timers.
+local timers = {}
+local named = {}
+local next_id = 1
+
+local function add(delay, fn, interval, reps, name)
+	local id = next_id
+	next_id = next_id + 1
+	schedule_timer(id, delay)
+
+	timers[id] = {
+		fn = fn,
+		interval = interval,
+		reps = reps,
+		name = name,
+		owner = plugin_of(fn),
+	}
+
+	return id
+end
+
+--- Call `fn` once after `delay` seconds.
+---@param delay number
+---@param fn fun()
+function timer.simple(delay, fn)
+	add(delay, fn, nil, 1)
+end
+
+--- Call `fn` every `interval` seconds, `reps` times or forever if it's 0.
+--- Replaces any other timer with the same name.
+---@param name string
+---@param interval number
+---@param reps integer
+---@param fn fun()
+function timer.create(name, interval, reps, fn)
+	timer.remove(name)
+	named[name] = add(interval, fn, interval, reps, name)
+end
+
+--- Stop the timer with this name.
+---@param name string
+function timer.remove(name)
+	local id = named[name]
+	if not id then return end
+
+	timers[id] = nil
+	named[name] = nil
+end
+
+---@param name string
+---@return boolean
+function timer.exists(name)
+	return named[name] ~= nil
+end
+
+--- Pause the current coroutine for `sec` seconds, see `timer.run`.
+---@param sec number
+function timer.sleep(sec)
+	local co = coroutine.running()
+	if not co then
+		error("timer.sleep can only be used in a coroutine, see timer.run", 2)
+	end
+
+	timer.simple(sec, function()
+		local ok, err = coroutine.resume(co)
+		if not ok then error(err, 0) end
+	end)
+
+	coroutine.yield()
+end
+
+--- Run `fn` in a coroutine, so that it may use `timer.sleep`.
+---@param fn fun(...)
+---@param ... any
+function timer.run(fn, ...)
+	local ok, err = coroutine.resume(coroutine.create(fn), ...)
+	if not ok then error(err, 0) end
+end
+
+return {
+	timer = function(id)
+		local t = timers[id]
+		if not t then return end
+
+		if t.reps == 1 or (t.owner and is_disabled(t.owner)) then
+			timers[id] = nil
+			if t.name then named[t.name] = nil end
+
+			if t.reps ~= 1 then return end
+		elseif t.reps > 1 then
+			t.reps = t.reps - 1
+		end
+
+		-- Errors are only logged, so that a repeating timer keeps going.
+		call_hook(t.owner, t.fn)
+
+		return timers[id] and t.interval or nil
+	end,
+	reset = function()
+		timers = {}
+		named = {}
+	end
+}
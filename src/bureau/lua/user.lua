@@ -1,5 +1,7 @@
--- Add lua and plugin directories to loader path.
-package.path = "plugins/?.lua;" .. package.path
+-- Add lua and plugin directories to loader path, safe mode has no `package` and plugins get their own `require`.
+if package then
+	package.path = "plugins/?.lua;" .. package.path
+end
 
 local ftbl = ...
 
@@ -7,6 +9,8 @@ local set_pos = ftbl.set_pos
 local set_rot = ftbl.set_rot
 local send_msg = ftbl.send_msg
 local send_packet = ftbl.send_packet
+local set_master = ftbl.set_master
+local set_voice_muted = ftbl.set_voice_muted
 local disconnect = ftbl.disconnect
 
 ---@class User
@@ -14,8 +18,11 @@ local disconnect = ftbl.disconnect
 ---@field name string
 ---@field avatar string
 ---@field ip string
+---@field state string
 ---@field _pos Vector
 ---@field _rot Basis
+---@field _character Character?
+---@field _voice_muted boolean?
 local user_meta = {}
 user_meta.__index = user_meta
 
@@ -49,6 +56,39 @@ function user_meta:getRot()
 	return self._rot:clone()
 end
 
+--- Get a copy of the last character data the User sent that could be parsed, nil if there is none.
+---@return Character?
+function user_meta:getCharacter()
+	local c = self._character
+	if not c then return end
+
+	local parts = {}
+	for i, part in ipairs(c.parts) do
+		parts[i] = { part[1], part[2] }
+	end
+
+	return {
+		sleep = c.sleep,
+		avatar = c.avatar,
+		parts = parts,
+		minutes = c.minutes,
+		medal = c.medal,
+	}
+end
+
+--- Stop or resume passing the User's voice state on to others.
+---@param muted boolean
+function user_meta:setVoiceMuted(muted)
+	self._voice_muted = muted
+	set_voice_muted(self.id, muted)
+end
+
+--- Check if the User's voice is muted.
+---@return boolean
+function user_meta:isVoiceMuted()
+	return self._voice_muted == true
+end
+
 --- Send a packet to the User.
 ---@param msg string
 function user_meta:sendMsg(msg)
@@ -66,6 +106,18 @@ function user_meta:__tostring()
 end
 
 local users = {}
+local master_id
+
+--- Check if the User is the master, which answers area requests for the other clients.
+---@return boolean
+function user_meta:isMaster()
+	return self.id == master_id
+end
+
+--- Make the User the master, the previous master is told it no longer is.
+function user_meta:setMaster()
+	set_master(self.id)
+end
 
 ---@diagnostic disable-next-line: lowercase-global
 user_manager = {}
@@ -87,5 +139,15 @@ function user_manager.get(id)
 	return users[id]
 end
 
-return users, user_meta
+--- Get the master user, if there is one.
+---@return User?
+function user_manager.getMaster()
+	return users[master_id]
+end
+
+local function master_changed(id)
+	master_id = id
+end
+
+return users, user_meta, master_changed
 
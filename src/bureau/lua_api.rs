@@ -1,21 +1,45 @@
 use std::{
-	cell::RefCell,
+	cell::{Cell, RefCell},
+	collections::{BTreeMap, HashMap, HashSet},
 	fs,
-	io::{self, ErrorKind},
+	io::ErrorKind,
 	net::{IpAddr, SocketAddr},
-	path::PathBuf,
+	path::{Path, PathBuf},
 	rc::Rc,
+	time::{Duration, Instant},
 };
 
-use mlua::{ChunkMode, FromLuaMulti, Function, IntoLuaMulti, Lua, RegistryKey, Table};
+use mlua::{
+	ChunkMode, FromLua, FromLuaMulti, Function, HookTriggers, IntoLua, IntoLuaMulti, Lua,
+	LuaOptions, MultiValue, RegistryKey, StdLib, Table, Value,
+};
 use spark_macro::include_lua;
 
 use super::{
+	character::{BodyPart, CharacterData, Medal},
+	codec::{Common, GeneralMessage},
+	emulated_master::{MasterReply, ReplyTarget},
+	json::{self, Json},
+	manifest::{self, Manifest, Plugin},
 	math::{Mat3, Vector3},
-	protocol::{ByteWriter, MsgCommon, Strategy},
+	plugin::{self, HookStats, PluginOptions},
+	plugin_memory::PluginMemory,
+	protocol::{ByteWriter, Strategy, UserState},
+	storage,
+	timer_wheel::TimerWheel,
 	user_list::UserList,
 };
 
+const TIMER_RESOLUTION: Duration = Duration::from_millis(10);
+
+/// Named registry value holding what each plugin's init.lua returned, keyed by plugin.
+const PLUGIN_EXPORTS: &str = "plugin_exports";
+/// Named registry value holding the metatable of users, which sandboxed plugins may not get at.
+const USER_META: &str = "user_meta";
+
+/// Instructions between checks of the time budget.
+const BUDGET_CHECK_INTERVAL: u32 = 1000;
+
 type EventQueue = Rc<RefCell<Vec<(i32, LuaEvent)>>>;
 
 pub enum LuaEvent {
@@ -23,29 +47,182 @@ pub enum LuaEvent {
 	SetRot(Mat3),
 	SendMsg(String),
 	SendPacket(ByteWriter),
+	SetMaster,
+	SetVoiceMuted(bool),
+	/// Reload every plugin once the current events are handled.
+	ReloadPlugins,
+	/// Sent by the Bureau, the id is who `strategy` is relative to.
+	ApplSpecific {
+		strategy: Strategy,
+		method: String,
+		strarg: String,
+		intarg: i32,
+	},
 	Disconnect,
 }
 
+/// A function from hook.lua that runs the hooks plugins added for an event.
+struct Hook {
+	name: &'static str,
+	func: RegistryKey,
+}
+
+impl Hook {
+	fn get(lua: &Lua, tbl: &Table, name: &'static str) -> mlua::Result<Self> {
+		Ok(Self {
+			name,
+			func: lua.create_registry_value::<Function>(tbl.get(name)?)?,
+		})
+	}
+}
+
+/// Lua timers by id, along with their deadline.
+type Timers = Rc<RefCell<TimerWheel<(u64, Instant)>>>;
+
+type Stats = Rc<RefCell<HashMap<&'static str, HookStats>>>;
+
+/// Shared with the Lua debug hook that enforces `PluginOptions::budget`.
+#[derive(Default)]
+struct Budget {
+	limit: Option<Duration>,
+	deadline: Cell<Option<Instant>>,
+	/// A plugin's hook is running, hooks it runs itself with `hook.run` share its deadline.
+	in_hook: Cell<bool>,
+	/// Plugin that was running when the deadline passed.
+	offender: RefCell<Option<String>>,
+	/// The hook is checking every instruction since the deadline passed.
+	escalated: Cell<bool>,
+}
+
+impl Budget {
+	/// Give the budget to a hook that's about to run, unless it's ran by another hook.
+	fn start(&self) -> bool {
+		let nested = self.in_hook.replace(true);
+		if !nested {
+			self.deadline
+				.set(self.limit.map(|limit| Instant::now() + limit));
+		}
+
+		nested
+	}
+
+	fn exceeded(&self) -> bool {
+		self.deadline
+			.get()
+			.is_some_and(|deadline| Instant::now() >= deadline)
+	}
+}
+
 struct Funcs {
-	think: RegistryKey,
-	user_connect: RegistryKey,
-	new_user: RegistryKey,
-	pos_update: RegistryKey,
-	trans_update: RegistryKey,
-	chat_send: RegistryKey,
-	name_change: RegistryKey,
-	avatar_change: RegistryKey,
-	private_chat: RegistryKey,
-	aura_enter: RegistryKey,
-	aura_leave: RegistryKey,
-	user_disconnect: RegistryKey,
-	plugins_loaded: RegistryKey,
+	think: Hook,
+	user_connect: Hook,
+	new_user: Hook,
+	pos_update: Hook,
+	trans_update: Hook,
+	chat_send: Hook,
+	name_change: Hook,
+	avatar_change: Hook,
+	private_chat: Hook,
+	aura_enter: Hook,
+	aura_leave: Hook,
+	user_disconnect: Hook,
+	appl_specific: Hook,
+	vc_register: Hook,
+	voice_state: Hook,
+	character_update: Hook,
+	state_change: Hook,
+	master_elect: Hook,
+	master_change: Hook,
+	master_request: Hook,
+	plugins_loaded: Hook,
+	reload: Hook,
+	disable_plugin: RegistryKey,
+	reset: RegistryKey,
+	timer: Hook,
+	reset_timers: RegistryKey,
 }
 
 impl Funcs {
-	pub fn init(lua: &mut Lua, event_queue: &EventQueue) -> mlua::Result<Self> {
+	pub fn init(
+		lua: &mut Lua,
+		event_queue: &EventQueue,
+		stats: &Stats,
+		timers: &Timers,
+		budget: &Rc<Budget>,
+		memory: &Rc<PluginMemory>,
+	) -> mlua::Result<Self> {
 		let tbl = lua.create_table()?;
 
+		let plugin_storage = |lua: &Lua| {
+			calling_plugin(lua)
+				.ok_or_else(|| mlua::Error::runtime("storage can only be used by plugins"))
+		};
+
+		tbl.set(
+			"storage_get",
+			lua.create_function(move |lua, key: String| {
+				Ok(storage::get(&plugin_storage(lua)?, &key))
+			})?,
+		)?;
+
+		tbl.set(
+			"storage_set",
+			lua.create_function(move |lua, (key, value): (String, Json)| {
+				let value = (value != Json::Null).then_some(value);
+				storage::set(&plugin_storage(lua)?, &key, value);
+				Ok(())
+			})?,
+		)?;
+
+		tbl.set(
+			"storage_keys",
+			lua.create_function(move |lua, ()| Ok(storage::keys(&plugin_storage(lua)?)))?,
+		)?;
+
+		tbl.set(
+			"plugin_export",
+			lua.create_function(|lua, name: String| {
+				lua.named_registry_value::<Option<Table>>(PLUGIN_EXPORTS)?
+					.map_or(Ok(Value::Nil), |exports| exports.get(name))
+			})?,
+		)?;
+
+		tbl.set(
+			"schedule_timer",
+			lua.create_function({
+				let timers = timers.clone();
+				move |_, (id, delay): (u64, f64)| {
+					let deadline = Duration::try_from_secs_f64(delay)
+						.ok()
+						.and_then(|delay| Instant::now().checked_add(delay))
+						.ok_or_else(|| mlua::Error::runtime("invalid timer delay"))?;
+
+					timers.borrow_mut().insert(deadline, (id, deadline));
+					Ok(())
+				}
+			})?,
+		)?;
+
+		tbl.set(
+			"hook_stats",
+			lua.create_function({
+				let stats = stats.clone();
+				move |lua, ()| {
+					let tbl = lua.create_table()?;
+					for (name, stats) in stats.borrow().iter() {
+						let entry = lua.create_table()?;
+						entry.set("calls", stats.calls)?;
+						entry.set("total", stats.total.as_secs_f64() * 1000.0)?;
+						entry.set("max", stats.max.as_secs_f64() * 1000.0)?;
+						entry.set("overruns", stats.overruns)?;
+						tbl.set(*name, entry)?;
+					}
+
+					Ok(tbl)
+				}
+			})?,
+		)?;
+
 		tbl.set(
 			"set_pos",
 			lua.create_function({
@@ -99,6 +276,62 @@ impl Funcs {
 			})?,
 		)?;
 
+		tbl.set(
+			"set_master",
+			lua.create_function({
+				let event_queue = event_queue.clone();
+				move |_, id: i32| {
+					event_queue.borrow_mut().push((id, LuaEvent::SetMaster));
+					Ok(())
+				}
+			})?,
+		)?;
+
+		tbl.set(
+			"set_voice_muted",
+			lua.create_function({
+				let event_queue = event_queue.clone();
+				move |_, (id, muted): (i32, bool)| {
+					event_queue
+						.borrow_mut()
+						.push((id, LuaEvent::SetVoiceMuted(muted)));
+					Ok(())
+				}
+			})?,
+		)?;
+
+		tbl.set(
+			"send_appl_specific",
+			lua.create_function({
+				let event_queue = event_queue.clone();
+				move |_, (strategy, id, method, strarg, intarg): (u8, i32, String, String, i32)| {
+					event_queue.borrow_mut().push((
+						id,
+						LuaEvent::ApplSpecific {
+							strategy: strategy.into(),
+							method,
+							strarg,
+							intarg,
+						},
+					));
+					Ok(())
+				}
+			})?,
+		)?;
+
+		tbl.set(
+			"reload_plugins",
+			lua.create_function({
+				let event_queue = event_queue.clone();
+				move |_, ()| {
+					event_queue
+						.borrow_mut()
+						.push((-9999, LuaEvent::ReloadPlugins));
+					Ok(())
+				}
+			})?,
+		)?;
+
 		tbl.set(
 			"disconnect",
 			lua.create_function({
@@ -113,50 +346,320 @@ impl Funcs {
 		lua.load(include_lua!("lua/vector.lua").as_ref()).exec()?;
 		lua.load(include_lua!("lua/basis.lua").as_ref()).exec()?;
 
-		let (users, user_meta): (Table, Table) =
-			lua.load(include_lua!("lua/user.lua").as_ref()).call(tbl)?;
+		lua.load(include_lua!("lua/bureau.lua").as_ref())
+			.call::<_, ()>(&tbl)?;
+		lua.load(include_lua!("lua/storage.lua").as_ref())
+			.call::<_, ()>(&tbl)?;
+		lua.load(include_lua!("lua/plugins.lua").as_ref())
+			.call::<_, ()>(&tbl)?;
+
+		let (users, user_meta, set_master): (Table, Table, Function) =
+			lua.load(include_lua!("lua/user.lua").as_ref()).call(&tbl)?;
+		lua.set_named_registry_value(USER_META, &user_meta)?;
+
+		let plugin_of = lua.create_function(|_, f: Function| {
+			Ok(f.info().source.as_deref().and_then(plugin::name_of))
+		})?;
+
+		// Runs a single hook, so that an error in one plugin doesn't keep the hooks of others from running.
+		let call_hook = lua.create_function({
+			let budget = budget.clone();
+			let memory = memory.clone();
+			move |lua, (owner, f, args): (Option<String>, Function, MultiValue)| {
+				let nested = budget.start();
+				let result = memory.call::<MultiValue>(lua, owner.as_deref(), f, args);
+				budget.in_hook.set(nested);
+
+				match result {
+					Ok(mut ret) => {
+						ret.push_front(Value::Boolean(true));
+						Ok(ret)
+					}
+					// Running past the budget aborts the whole event.
+					Err(e) if budget.exceeded() => {
+						let mut offender = budget.offender.borrow_mut();
+						if offender.is_none() {
+							*offender = owner;
+						}
+						Err(e)
+					}
+					Err(e) => {
+						match owner {
+							Some(owner) => eprintln!("Lua Error in plugin '{}': {}", owner, e),
+							None => eprintln!("Lua Error: {}", e),
+						}
+						Ok(MultiValue::from_vec(vec![Value::Boolean(false)]))
+					}
+				}
+			}
+		})?;
+
+		let hooks: Table = lua.load(include_lua!("lua/hook.lua").as_ref()).call((
+			users,
+			user_meta,
+			set_master,
+			plugin_of.clone(),
+			call_hook.clone(),
+		))?;
+
+		let timer: Table = lua.load(include_lua!("lua/timer.lua").as_ref()).call((
+			tbl,
+			plugin_of,
+			hooks.get::<_, Function>("is_disabled")?,
+			call_hook,
+		))?;
+		let tbl = hooks;
+
+		Ok(Self {
+			think: Hook::get(lua, &tbl, "think")?,
+			user_connect: Hook::get(lua, &tbl, "user_connect")?,
+			new_user: Hook::get(lua, &tbl, "new_user")?,
+			pos_update: Hook::get(lua, &tbl, "pos_update")?,
+			trans_update: Hook::get(lua, &tbl, "trans_update")?,
+			chat_send: Hook::get(lua, &tbl, "chat_send")?,
+			name_change: Hook::get(lua, &tbl, "name_change")?,
+			avatar_change: Hook::get(lua, &tbl, "avatar_change")?,
+			private_chat: Hook::get(lua, &tbl, "private_chat")?,
+			aura_enter: Hook::get(lua, &tbl, "aura_enter")?,
+			aura_leave: Hook::get(lua, &tbl, "aura_leave")?,
+			user_disconnect: Hook::get(lua, &tbl, "user_disconnect")?,
+			appl_specific: Hook::get(lua, &tbl, "appl_specific")?,
+			vc_register: Hook::get(lua, &tbl, "vc_register")?,
+			voice_state: Hook::get(lua, &tbl, "voice_state")?,
+			character_update: Hook::get(lua, &tbl, "character_update")?,
+			state_change: Hook::get(lua, &tbl, "state_change")?,
+			master_elect: Hook::get(lua, &tbl, "master_elect")?,
+			master_change: Hook::get(lua, &tbl, "master_change")?,
+			master_request: Hook::get(lua, &tbl, "master_request")?,
+			plugins_loaded: Hook::get(lua, &tbl, "plugins_loaded")?,
+			reload: Hook::get(lua, &tbl, "reload")?,
+			disable_plugin: lua.create_registry_value::<Function>(tbl.get("disable_plugin")?)?,
+			reset: lua.create_registry_value::<Function>(tbl.get("reset")?)?,
+			timer: Hook::get(lua, &timer, "timer")?,
+			reset_timers: lua.create_registry_value::<Function>(timer.get("reset")?)?,
+		})
+	}
+}
+
+impl<'lua> FromLua<'lua> for MasterReply {
+	fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> mlua::Result<Self> {
+		let tbl = Table::from_lua(value, lua)?;
+
+		Ok(Self {
+			target: match tbl.get::<_, Option<String>>("to")?.as_deref() {
+				Some("all") => ReplyTarget::All,
+				_ => ReplyTarget::Sender,
+			},
+			method: tbl.get("method")?,
+			strarg: tbl.get::<_, Option<String>>("strarg")?.unwrap_or_default(),
+			intarg: tbl.get("intarg")?,
+		})
+	}
+}
+
+impl<'lua> IntoLua<'lua> for &CharacterData {
+	fn into_lua(self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+		let parts =
+			lua.create_sequence_from(self.parts.iter().map(|part| [part.color, part.scale]))?;
+
+		let tbl = lua.create_table()?;
+		tbl.set("sleep", self.sleep)?;
+		tbl.set("avatar", self.avatar)?;
+		tbl.set("parts", parts)?;
+		tbl.set("minutes", self.minutes)?;
+		tbl.set("medal", self.medal as u8)?;
+
+		Ok(Value::Table(tbl))
+	}
+}
+
+impl<'lua> FromLua<'lua> for CharacterData {
+	fn from_lua(value: Value<'lua>, lua: &'lua Lua) -> mlua::Result<Self> {
+		let tbl = Table::from_lua(value, lua)?;
+		let invalid = |what: &str| mlua::Error::runtime(format!("invalid character {}", what));
+
+		let parts = tbl
+			.get::<_, Vec<[u8; 2]>>("parts")?
+			.into_iter()
+			.map(|[color, scale]| {
+				if color >= 36 || scale >= 36 {
+					return Err(invalid("part, values have to be below 36"));
+				}
 
-		let tbl: Table = lua
-			.load(include_lua!("lua/hook.lua").as_ref())
-			.call((users, user_meta))?;
+				Ok(BodyPart { color, scale })
+			})
+			.collect::<mlua::Result<Vec<_>>>()?;
 
 		Ok(Self {
-			think: lua.create_registry_value::<Function>(tbl.get("think")?)?,
-			user_connect: lua.create_registry_value::<Function>(tbl.get("user_connect")?)?,
-			new_user: lua.create_registry_value::<Function>(tbl.get("new_user")?)?,
-			pos_update: lua.create_registry_value::<Function>(tbl.get("pos_update")?)?,
-			trans_update: lua.create_registry_value::<Function>(tbl.get("trans_update")?)?,
-			chat_send: lua.create_registry_value::<Function>(tbl.get("chat_send")?)?,
-			name_change: lua.create_registry_value::<Function>(tbl.get("name_change")?)?,
-			avatar_change: lua.create_registry_value::<Function>(tbl.get("avatar_change")?)?,
-			private_chat: lua.create_registry_value::<Function>(tbl.get("private_chat")?)?,
-			aura_enter: lua.create_registry_value::<Function>(tbl.get("aura_enter")?)?,
-			aura_leave: lua.create_registry_value::<Function>(tbl.get("aura_leave")?)?,
-			user_disconnect: lua.create_registry_value::<Function>(tbl.get("user_disconnect")?)?,
-			plugins_loaded: lua.create_registry_value::<Function>(tbl.get("plugins_loaded")?)?,
+			sleep: tbl.get("sleep")?,
+			avatar: tbl.get("avatar")?,
+			parts,
+			minutes: tbl.get("minutes")?,
+			medal: Medal::from_u8(tbl.get("medal")?).ok_or_else(|| invalid("medal"))?,
 		})
 	}
 }
 
+/// Convert a Lua value to JSON, sequences become arrays and other tables objects.
+fn json_from_lua(value: Value, depth: usize) -> mlua::Result<Json> {
+	let invalid = |what: &str| mlua::Error::runtime(format!("can't store {}", what));
+
+	Ok(match value {
+		Value::Nil => Json::Null,
+		Value::Boolean(b) => Json::Bool(b),
+		Value::Integer(i) => Json::Number(i as f64),
+		Value::Number(n) if n.is_finite() => Json::Number(n),
+		Value::String(s) => Json::String(s.to_str()?.to_string()),
+		Value::Table(tbl) => {
+			if depth >= json::MAX_DEPTH {
+				return Err(invalid("tables nested this deeply"));
+			}
+
+			// Tables with only positive integer keys are arrays, where the holes left by a `null`
+			// become `null` again. Sparse ones are kept as objects so they don't blow up in size.
+			let mut count = 0;
+			let mut max = 0;
+			for pair in tbl.clone().pairs::<Value, Value>() {
+				match pair?.0 {
+					Value::Integer(i) if i >= 1 => max = max.max(i as usize),
+					_ => {
+						max = 0;
+						break;
+					}
+				}
+				count += 1;
+			}
+			if max > 0 && max <= count * 2 {
+				return (1..=max)
+					.map(|i| json_from_lua(tbl.raw_get(i)?, depth + 1))
+					.collect::<mlua::Result<_>>()
+					.map(Json::Array);
+			}
+
+			let mut values = BTreeMap::new();
+			for pair in tbl.pairs::<Value, Value>() {
+				let (key, value) = pair?;
+				// Numbers keys that aren't part of a sequence come back as strings.
+				let key = match key {
+					Value::String(s) => s.to_str()?.to_string(),
+					Value::Integer(i) => i.to_string(),
+					Value::Number(n) if n.is_finite() => n.to_string(),
+					_ => return Err(invalid(&format!("{} keys", key.type_name()))),
+				};
+
+				values.insert(key, json_from_lua(value, depth + 1)?);
+			}
+
+			Json::Object(values)
+		}
+		_ => return Err(invalid(value.type_name())),
+	})
+}
+
+impl<'lua> FromLua<'lua> for Json {
+	fn from_lua(value: Value<'lua>, _: &'lua Lua) -> mlua::Result<Self> {
+		json_from_lua(value, 0)
+	}
+}
+
+impl<'lua> IntoLua<'lua> for Json {
+	fn into_lua(self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+		Ok(match self {
+			Json::Null => Value::Nil,
+			Json::Bool(b) => Value::Boolean(b),
+			Json::Number(n) => Value::Number(n),
+			Json::String(s) => Value::String(lua.create_string(s)?),
+			Json::Array(values) => Value::Table(lua.create_sequence_from(values)?),
+			Json::Object(values) => Value::Table(lua.create_table_from(values)?),
+		})
+	}
+}
+
+/// The innermost plugin function on the Lua stack.
+fn calling_plugin(lua: &Lua) -> Option<String> {
+	(0..)
+		.map_while(|level| lua.inspect_stack(level))
+		.find_map(|debug| debug.source().source.as_deref().and_then(plugin::name_of))
+}
+
 pub struct LuaApi {
 	lua: Lua,
+	/// Dropped after `lua`, whose allocator uses it until the state is closed.
+	memory: Rc<PluginMemory>,
 	funcs: Funcs,
 	event_queue: EventQueue,
+	options: PluginOptions,
+	budget: Rc<Budget>,
+	stats: Stats,
+	/// Times each plugin ran past the budget.
+	strikes: RefCell<HashMap<String, u32>>,
+	timers: Timers,
 }
 
-fn do_file(lua: &mut Lua, path: PathBuf) -> mlua::Result<()> {
+/// Run a plugin's init.lua, returning what it exports.
+fn do_file<'lua>(
+	lua: &'lua Lua,
+	memory: &PluginMemory,
+	plugin: &str,
+	path: PathBuf,
+	env: Option<Table<'lua>>,
+) -> mlua::Result<Value<'lua>> {
 	let chunkname = format!("={:?}", path);
 
 	let data = fs::read(path)?;
-	lua.load(data)
-		.set_mode(ChunkMode::Text)
-		.set_name(chunkname)
-		.exec()?;
+	let chunk = lua.load(data).set_mode(ChunkMode::Text).set_name(chunkname);
+	let f = match env {
+		Some(env) => chunk.set_environment(env).into_function()?,
+		None => chunk.into_function()?,
+	};
 
-	Ok(())
+	memory.call(lua, Some(plugin), f, ())
 }
 
-fn load_plugins(lua: &mut Lua) -> io::Result<()> {
+/// Abort calls into Lua that run past `budget.deadline`, checking every `interval` instructions.
+fn set_budget_hook(lua: &Lua, budget: Rc<Budget>, interval: u32) {
+	lua.set_hook(
+		HookTriggers::new().every_nth_instruction(interval),
+		move |lua, _| {
+			match budget.deadline.get() {
+				Some(deadline) if Instant::now() >= deadline => (),
+				_ => return Ok(()),
+			}
+
+			// Blame the innermost plugin function on the stack.
+			let offender = calling_plugin(lua);
+			if offender.is_some() {
+				*budget.offender.borrow_mut() = offender;
+			}
+
+			// A plugin calling pcall in a loop could keep catching the error,
+			// checking every instruction makes sure it's raised in the loop itself too.
+			if interval != 1 {
+				budget.escalated.set(true);
+				set_budget_hook(lua, budget.clone(), 1);
+			}
+
+			Err(mlua::Error::runtime("ran past its time budget"))
+		},
+	);
+}
+
+/// Create the function that makes the environment of a plugin in safe mode.
+fn sandbox(lua: &Lua) -> mlua::Result<Function<'_>> {
+	let tbl = lua.create_table()?;
+
+	tbl.set(
+		"path_in",
+		lua.create_function(|_, (dir, path): (String, String)| {
+			Ok(plugin::path_in(Path::new(&dir), &path).map(|p| p.to_string_lossy().into_owned()))
+		})?,
+	)?;
+	tbl.set("user_meta", lua.named_registry_value::<Table>(USER_META)?)?;
+
+	lua.load(include_lua!("lua/sandbox.lua").as_ref()).call(tbl)
+}
+
+fn load_plugins(lua: &Lua, options: PluginOptions, memory: &PluginMemory) -> anyhow::Result<()> {
 	let read_dir = match fs::read_dir("plugins") {
 		Ok(r) => r,
 		Err(err) => {
@@ -165,43 +668,193 @@ fn load_plugins(lua: &mut Lua) -> io::Result<()> {
 				return Ok(());
 			}
 
-			return Err(err);
+			return Err(err.into());
 		}
 	};
 
+	let mut plugins = Vec::new();
 	for file in read_dir {
 		let file = file?;
 
-		if file.file_type()?.is_dir() {
-			let path = file.path();
-			let initpath = path.join("init.lua");
+		if !file.file_type()?.is_dir() {
+			continue;
+		}
 
-			if !initpath.is_file() {
-				eprintln!("{:?} is missing an init.lua and will not be loaded.", path);
-				continue;
-			}
+		let dir = file.path();
+		let Some(name) = file.file_name().to_str().map(str::to_string) else {
+			eprintln!("{:?} is not valid UTF-8 and will not be loaded.", dir);
+			continue;
+		};
+
+		if !dir.join("init.lua").is_file() {
+			eprintln!("{:?} is missing an init.lua and will not be loaded.", dir);
+			continue;
+		}
+
+		match Manifest::load(&dir) {
+			Ok(manifest) => plugins.push(Plugin {
+				name,
+				dir,
+				manifest,
+			}),
+			Err(e) => eprintln!("Plugin '{}' will not be loaded, {:#}", name, e),
+		}
+	}
+
+	let plugins = manifest::load_order(plugins);
 
-			if let Err(e) = do_file(lua, initpath) {
-				eprintln!("Error while loading plugin {:?}, {}", path, e)
+	let new_env = if options.safe {
+		Some(sandbox(lua)?)
+	} else {
+		None
+	};
+
+	let exports = lua.create_table()?;
+	lua.set_named_registry_value(PLUGIN_EXPORTS, &exports)?;
+	let mut loaded = HashSet::new();
+
+	for plugin in plugins {
+		if let Some(dependency) = plugin
+			.manifest
+			.dependencies
+			.iter()
+			.find(|dependency| !loaded.contains(*dependency))
+		{
+			eprintln!(
+				"Plugin '{}' will not be loaded, its dependency '{}' failed to load.",
+				plugin.name, dependency
+			);
+			continue;
+		}
+
+		let env = match &new_env {
+			Some(new_env) => Some(new_env.call(plugin.dir.to_string_lossy())?),
+			None => None,
+		};
+
+		match do_file(lua, memory, &plugin.name, plugin.dir.join("init.lua"), env) {
+			Ok(export) => {
+				exports.set(plugin.name.as_str(), export)?;
+
+				let manifest = &plugin.manifest;
+				let mut info = format!("Loaded plugin '{}'", plugin.display_name());
+				if let Some(version) = &manifest.version {
+					info += &format!(" {}", version);
+				}
+				if let Some(author) = &manifest.author {
+					info += &format!(" by {}", author);
+				}
+				println!("{}.", info);
+
+				loaded.insert(plugin.name);
 			}
+			Err(e) => eprintln!("Error while loading plugin {:?}, {}", plugin.dir, e),
 		}
 	}
 
 	Ok(())
 }
 
+/// Whether an ApplSpecific may be passed on, and its new method, strarg and intarg.
+pub type ApplSpecificRewrite = (bool, Option<String>, Option<String>, Option<i32>);
+
+/// Send an ApplSpecific from the Bureau, aura strategies are relative to `id`.
+fn send_appl_specific(
+	user_list: &mut UserList,
+	id: i32,
+	strategy: Strategy,
+	method: String,
+	strarg: String,
+	intarg: i32,
+) {
+	let stream = GeneralMessage::common(
+		-9999,
+		id,
+		strategy,
+		Common::ApplSpecific {
+			unknown: 2,
+			method,
+			strarg,
+			intarg,
+		},
+	)
+	.encode();
+
+	match strategy {
+		Strategy::AuraClients | Strategy::AuraClientsExceptSender => {
+			let Some(user) = user_list.get_mut(&id) else {
+				return;
+			};
+			if matches!(strategy, Strategy::AuraClients) {
+				user.send(&stream);
+			}
+
+			user_list.for_aura(id, |_, other| other.send(&stream));
+		}
+		Strategy::SpecificClient => {
+			if let Some(user) = user_list.get_mut(&id) {
+				user.send(&stream);
+			}
+		}
+		Strategy::AllClients | Strategy::AllClientsExceptSender => {
+			let include_id = matches!(strategy, Strategy::AllClients);
+			for user in user_list.values_mut() {
+				if include_id || user.id != id {
+					user.send(&stream);
+				}
+			}
+		}
+		_ => (),
+	}
+}
+
 impl LuaApi {
-	pub fn new() -> anyhow::Result<Self> {
-		let mut lua = unsafe { Lua::unsafe_new() };
+	pub fn new(options: PluginOptions) -> anyhow::Result<Self> {
+		let mut lua = if options.safe {
+			// Plugins only get parts of `io` and `os` through their environment, and no `jit`.
+			let libs =
+				StdLib::JIT
+					| StdLib::TABLE | StdLib::IO
+					| StdLib::OS | StdLib::STRING
+					| StdLib::BIT | StdLib::MATH;
+
+			Lua::new_with(libs, LuaOptions::default())?
+		} else {
+			unsafe { Lua::unsafe_new() }
+		};
 
 		let event_queue = Rc::new(RefCell::new(Vec::new()));
-		let funcs = Funcs::init(&mut lua, &event_queue)?;
-		load_plugins(&mut lua)?;
+		let stats = Stats::default();
+		let timers = Rc::new(RefCell::new(TimerWheel::new(TIMER_RESOLUTION, 512)));
+		let budget = Rc::new(Budget {
+			limit: options.budget,
+			..Default::default()
+		});
+		let memory = Rc::new(PluginMemory::new(options.memory_limit));
+		if options.memory_limit.is_some() {
+			memory.install(&lua)?;
+		}
+		let funcs = Funcs::init(&mut lua, &event_queue, &stats, &timers, &budget, &memory)?;
+
+		if options.budget.is_some() {
+			// Count hooks never run in JIT compiled code, so a compiled loop would never be stopped.
+			lua.load("jit.off()").exec()?;
+
+			set_budget_hook(&lua, budget.clone(), BUDGET_CHECK_INTERVAL);
+		}
+
+		load_plugins(&lua, options, &memory)?;
 
 		let lua_api = Self {
 			lua,
+			memory,
 			funcs,
 			event_queue,
+			options,
+			budget,
+			stats,
+			strikes: RefCell::default(),
+			timers,
 		};
 
 		lua_api.call::<_, ()>(&lua_api.funcs.plugins_loaded, ());
@@ -215,36 +868,155 @@ impl LuaApi {
 			return;
 		}
 
+		let mut master_changes = Vec::new();
+		let mut reload = false;
+
 		for (id, event) in event_queue.drain(..) {
+			let event = match event {
+				LuaEvent::ApplSpecific {
+					strategy,
+					method,
+					strarg,
+					intarg,
+				} => {
+					send_appl_specific(user_list, id, strategy, method, strarg, intarg);
+					continue;
+				}
+				LuaEvent::ReloadPlugins => {
+					reload = true;
+					continue;
+				}
+				event => event,
+			};
+
 			let Some(user) = user_list.get_mut(&id) else {
 				continue;
 			};
 
 			match event {
-				LuaEvent::SetPos(pos) => user.set_pos(pos),
+				LuaEvent::SetPos(pos) => {
+					user.set_pos(pos);
+					user_list.moved(id);
+				}
 				LuaEvent::SetRot(rot) => user.set_rot(rot),
-				LuaEvent::SendMsg(msg) => user.send(&ByteWriter::message_common(
-					user.id,
-					user.id,
-					MsgCommon::ChatSend,
-					Strategy::AllClientsExceptSender,
-					&ByteWriter::new(msg.len() + 1).write_string(&msg).bytes,
-				)),
+				LuaEvent::SendMsg(msg) => user.send(
+					&GeneralMessage::common(
+						user.id,
+						user.id,
+						Strategy::AllClientsExceptSender,
+						Common::ChatSend(msg),
+					)
+					.encode(),
+				),
 				LuaEvent::SendPacket(packet) => user.send(&packet),
+				LuaEvent::SetMaster => {
+					if !user.connected || user.joined_at.is_none() {
+						continue;
+					}
+
+					let old = user_list.set_master(Some(id));
+					if old != Some(id) {
+						master_changes.push((id, old));
+					}
+				}
+				LuaEvent::SetVoiceMuted(muted) => {
+					let unmuted = user.voice_muted && !muted;
+					user.voice_muted = muted;
+
+					// Catch the aura up on what was held back while muted.
+					let Some(data) = user.voice_state.clone().filter(|_| unmuted) else {
+						continue;
+					};
+					let stream = GeneralMessage::common(
+						id,
+						id,
+						Strategy::AuraClientsExceptSender,
+						Common::VoiceState(data),
+					)
+					.encode();
+					user_list.for_aura(id, |_, other| other.send(&stream));
+				}
 				LuaEvent::Disconnect => user.connected = false,
+				LuaEvent::ApplSpecific { .. } | LuaEvent::ReloadPlugins => unreachable!(),
 			}
 		}
 
 		event_queue.shrink_to_fit();
+		// Hooks may queue more events.
+		drop(event_queue);
+
+		for (new, old) in master_changes {
+			self.master_change(Some(new), old);
+		}
+
+		if reload {
+			self.reload(user_list);
+		}
+	}
+
+	/// Remove every hook and load the plugins again, then tell them about the users that are already connected.
+	pub fn reload(&self, user_list: &UserList) {
+		println!("Reloading plugins.");
+
+		for reset in [&self.funcs.reset, &self.funcs.reset_timers] {
+			if let Err(e) = self
+				.lua
+				.registry_value::<Function>(reset)
+				.and_then(|f| f.call::<_, ()>(()))
+			{
+				eprintln!("Lua Error: {}", e);
+				return;
+			}
+		}
+		self.strikes.borrow_mut().clear();
+		// Free what the old plugins used, so it doesn't count towards the new ones.
+		let _ = self.lua.gc_collect();
+
+		if let Err(e) = load_plugins(&self.lua, self.options, &self.memory) {
+			eprintln!("Failed to load plugins: {}", e);
+		}
+
+		let mut ids = user_list.keys().copied().collect::<Vec<_>>();
+		ids.sort_unstable();
+		self.call::<_, ()>(&self.funcs.reload, ids);
 	}
 
-	fn call<A, R>(&self, rk: &RegistryKey, args: A) -> Option<R>
+	fn call<A, R>(&self, hook: &Hook, args: A) -> Option<R>
 	where
 		A: for<'a> IntoLuaMulti<'a>,
 		R: for<'a> FromLuaMulti<'a>,
 	{
-		let f = self.lua.registry_value::<Function>(rk).ok()?;
-		match f.call::<A, R>(args) {
+		let f = self.lua.registry_value::<Function>(&hook.func).ok()?;
+
+		let start = Instant::now();
+		self.budget
+			.deadline
+			.set(self.budget.limit.map(|limit| start + limit));
+		let result = f.call::<A, R>(args);
+		self.budget.deadline.set(None);
+		let elapsed = start.elapsed();
+
+		if self.budget.escalated.replace(false) {
+			set_budget_hook(&self.lua, self.budget.clone(), BUDGET_CHECK_INTERVAL);
+		}
+
+		let offender = self.budget.offender.take();
+		{
+			let mut stats = self.stats.borrow_mut();
+			let stats = stats.entry(hook.name).or_default();
+			stats.calls += 1;
+			stats.total += elapsed;
+			stats.max = stats.max.max(elapsed);
+			if offender.is_some() {
+				stats.overruns += 1;
+			}
+		}
+
+		if let Some(plugin) = offender {
+			self.strike(&plugin, hook.name);
+		}
+
+		match result {
 			Ok(r) => Some(r),
 			Err(e) => {
 				eprintln!("Lua Error: {}", e);
@@ -253,6 +1025,60 @@ impl LuaApi {
 		}
 	}
 
+	/// Count a budget overrun against a plugin, disabling it once it has too many.
+	fn strike(&self, plugin: &str, hook: &str) {
+		let mut strikes = self.strikes.borrow_mut();
+		let strikes = strikes.entry(plugin.to_string()).or_default();
+		*strikes += 1;
+
+		eprintln!(
+			"Plugin '{}' ran past its time budget in {} ({}/{}).",
+			plugin, hook, strikes, self.options.strikes
+		);
+
+		if *strikes == self.options.strikes {
+			eprintln!("Plugin '{}' has been disabled.", plugin);
+
+			if let Err(e) = self
+				.lua
+				.registry_value::<Function>(&self.funcs.disable_plugin)
+				.and_then(|f| f.call::<_, ()>(plugin))
+			{
+				eprintln!("Lua Error: {}", e);
+			}
+		}
+	}
+
+	/// Time at which the next Lua timer may need to run.
+	pub fn next_timer(&self) -> Option<Instant> {
+		self.timers.borrow().next_deadline()
+	}
+
+	/// Run every Lua timer that is due.
+	pub fn run_timers(&self) {
+		let now = Instant::now();
+		let expired = self.timers.borrow_mut().expire(now);
+
+		for (id, deadline) in expired {
+			let Some(interval) = self.call::<_, Option<f64>>(&self.funcs.timer, id) else {
+				continue;
+			};
+
+			// Repeating timers are scheduled from their last deadline so they don't drift,
+			// but they're never made to catch up on runs they missed.
+			let Some(interval) = interval.and_then(|i| Duration::try_from_secs_f64(i).ok()) else {
+				continue;
+			};
+			let next = (deadline + interval.max(TIMER_RESOLUTION)).max(now);
+			self.timers.borrow_mut().insert(next, (id, next));
+		}
+	}
+
+	/// Timing of every event that hooks have ran for so far.
+	pub fn hook_stats(&self) -> HashMap<&'static str, HookStats> {
+		self.stats.borrow().clone()
+	}
+
 	pub fn think(&mut self) {
 		let _ = self.call::<_, ()>(&self.funcs.think, ());
 	}
@@ -302,4 +1128,174 @@ impl LuaApi {
 	pub fn user_disconnect(&self, id: i32) {
 		let _ = self.call::<_, Option<String>>(&self.funcs.user_disconnect, id);
 	}
+
+	/// Returns false if the message was vetoed, and whatever parts of it were rewritten.
+	pub fn appl_specific(
+		&self,
+		id: i32,
+		strategy: Strategy,
+		id2: i32,
+		method: &str,
+		strarg: &str,
+		intarg: i32,
+	) -> Option<ApplSpecificRewrite> {
+		self.call(
+			&self.funcs.appl_specific,
+			(id, u8::from(strategy), id2, method, strarg, intarg),
+		)
+	}
+
+	pub fn vc_register(&self, id: i32) -> bool {
+		self.call::<_, Option<bool>>(&self.funcs.vc_register, id)
+			.flatten()
+			.unwrap_or(true)
+	}
+
+	pub fn voice_state(&self, id: i32) -> bool {
+		self.call::<_, Option<bool>>(&self.funcs.voice_state, id)
+			.flatten()
+			.unwrap_or(true)
+	}
+
+	/// Returns false if the update was rejected, and the data to use instead if it was rewritten.
+	pub fn character_update(
+		&self,
+		id: i32,
+		character: &CharacterData,
+	) -> Option<(bool, Option<CharacterData>)> {
+		self.call::<_, (bool, Option<CharacterData>)>(&self.funcs.character_update, (id, character))
+	}
+
+	pub fn state_change(&self, id: i32, state: UserState, old: UserState) {
+		let _ = self.call::<_, ()>(&self.funcs.state_change, (id, state.name(), old.name()));
+	}
+
+	pub fn master_elect(&self, candidates: &[i32]) -> Option<i32> {
+		self.call::<_, Option<i32>>(&self.funcs.master_elect, candidates.to_vec())?
+	}
+
+	pub fn master_request(
+		&self,
+		id: i32,
+		method: &str,
+		strarg: &str,
+		intarg: i32,
+	) -> Option<Vec<MasterReply>> {
+		self.call::<_, Option<Vec<MasterReply>>>(
+			&self.funcs.master_request,
+			(id, method, strarg, intarg),
+		)?
+	}
+
+	pub fn master_change(&self, new: Option<i32>, old: Option<i32>) {
+		let _ = self.call::<_, ()>(&self.funcs.master_change, (new, old));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A LuaApi without plugins, hooks are added with `lua`.
+	fn lua_api(lua: &str) -> LuaApi {
+		let lua_api = LuaApi::new(PluginOptions::default()).unwrap();
+		lua_api.lua.load(lua).exec().unwrap();
+		lua_api
+	}
+
+	fn appl_specific(lua_api: &LuaApi, method: &str) -> Option<ApplSpecificRewrite> {
+		lua_api.appl_specific(1, Strategy::AllClients, -9999, method, "strarg", 5)
+	}
+
+	#[test]
+	fn appl_specific_hooks() {
+		let lua_api = lua_api(
+			r#"
+			hook.onApplSpecific(function(_, _, _, method)
+				if method == "drop" then return false end
+				if method == "allow" then return true end
+				if method == "rewrite" then return "rewritten", nil, 7 end
+			end)
+			"#,
+		);
+
+		let pass = Some((true, None, None, None));
+		assert_eq!(appl_specific(&lua_api, "other"), pass);
+		assert_eq!(appl_specific(&lua_api, "allow"), pass);
+		assert_eq!(
+			appl_specific(&lua_api, "drop"),
+			Some((false, None, None, None))
+		);
+		assert_eq!(
+			appl_specific(&lua_api, "rewrite"),
+			Some((true, Some("rewritten".to_string()), None, Some(7)))
+		);
+	}
+
+	#[test]
+	fn shared_metatables_are_hidden() {
+		let lua_api = LuaApi::new(PluginOptions {
+			safe: true,
+			..Default::default()
+		})
+		.unwrap();
+		lua_api.new_user(1, "user", "avatar", [127, 0, 0, 1].into());
+
+		let env: Table = sandbox(&lua_api.lua).unwrap().call("plugins/evil").unwrap();
+		lua_api
+			.lua
+			.load(
+				r#"
+				local user = user_manager.get(1)
+				assert(not pcall(function() getmetatable(user).__tostring = nil end))
+				assert(not pcall(setmetatable, user, {}))
+				assert(getmetatable(Vector()) == nil)
+				assert(getmetatable("") == nil)
+				"#,
+			)
+			.set_environment(env)
+			.exec()
+			.unwrap();
+
+		let name: String = lua_api
+			.lua
+			.load("return tostring(user_manager.get(1))")
+			.eval()
+			.unwrap();
+		assert_eq!(name, "User: 'user' (1)");
+	}
+
+	fn through_lua(text: &str) -> String {
+		let lua = Lua::new();
+		let value = Json::parse(text).unwrap().into_lua(&lua).unwrap();
+		Json::from_lua(value, &lua).unwrap().to_string()
+	}
+
+	#[test]
+	fn json_arrays() {
+		assert_eq!(through_lua("[1,2,3]"), "[1,2,3]");
+		assert_eq!(through_lua("[1,null,3]"), "[1,null,3]");
+		assert_eq!(through_lua("[null,2,3]"), "[null,2,3]");
+		// Mostly empty arrays are too sparse to tell from an object.
+		assert_eq!(through_lua("[null,null,3]"), r#"{"3":3}"#);
+		// Trailing nulls leave nothing behind in the table.
+		assert_eq!(through_lua("[1,null]"), "[1]");
+		assert_eq!(through_lua("[]"), "{}");
+		assert_eq!(through_lua(r#"{"1":1,"100":2}"#), r#"{"1":1,"100":2}"#);
+		assert_eq!(through_lua(r#"{"a":[{"b":null}]}"#), r#"{"a":[{}]}"#);
+	}
+
+	#[test]
+	fn json_number_keys() {
+		let lua = Lua::new();
+		let value = lua
+			.load("{ [1] = 'a', [100] = 'b', [1.5] = 'c' }")
+			.eval()
+			.unwrap();
+		let json = Json::from_lua(value, &lua).unwrap();
+		assert_eq!(json.to_string(), r#"{"1":"a","1.5":"c","100":"b"}"#);
+
+		let value = lua.load("{ 1 / 0 }").eval().unwrap();
+		assert!(Json::from_lua(value, &lua).is_err());
+	}
 }
@@ -0,0 +1,497 @@
+use std::{
+	collections::{HashMap, HashSet},
+	fs,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+
+/// Contents of a plugin's optional `plugin.toml`.
+///
+/// Only the part of TOML that's needed is supported: `key = value` pairs of strings, integers,
+/// booleans and arrays of strings, with `#` comments.
+pub struct Manifest {
+	/// Name shown in logs, plugins are still referred to by their directory name.
+	pub name: Option<String>,
+	pub version: Option<String>,
+	pub author: Option<String>,
+	/// Directory names of the plugins that have to be loaded first.
+	pub dependencies: Vec<String>,
+	/// Plugins with a higher priority load first, as long as their dependencies allow it.
+	pub priority: i64,
+	pub enabled: bool,
+}
+
+impl Default for Manifest {
+	fn default() -> Self {
+		Self {
+			name: None,
+			version: None,
+			author: None,
+			dependencies: Vec::new(),
+			priority: 0,
+			enabled: true,
+		}
+	}
+}
+
+enum TomlValue {
+	String(String),
+	Integer(i64),
+	Bool(bool),
+	Array(Vec<TomlValue>),
+}
+
+impl TomlValue {
+	fn type_name(&self) -> &'static str {
+		match self {
+			TomlValue::String(_) => "string",
+			TomlValue::Integer(_) => "integer",
+			TomlValue::Bool(_) => "boolean",
+			TomlValue::Array(_) => "array",
+		}
+	}
+}
+
+struct Parser<'a> {
+	text: &'a str,
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn rest(&self) -> &'a str {
+		&self.text[self.pos..]
+	}
+
+	fn line(&self) -> usize {
+		self.text[..self.pos].matches('\n').count() + 1
+	}
+
+	/// Skip spaces and tabs, and also newlines and comments if `newlines` is set.
+	fn skip(&mut self, newlines: bool) {
+		loop {
+			let rest = self.rest();
+			let trimmed = if newlines {
+				rest.trim_start()
+			} else {
+				rest.trim_start_matches([' ', '\t', '\r'])
+			};
+			self.pos += rest.len() - trimmed.len();
+
+			if newlines && trimmed.starts_with('#') {
+				self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+			} else {
+				return;
+			}
+		}
+	}
+
+	fn eat(&mut self, prefix: &str) -> bool {
+		let found = self.rest().starts_with(prefix);
+		if found {
+			self.pos += prefix.len();
+		}
+		found
+	}
+
+	fn key(&mut self) -> Option<&'a str> {
+		let start = self.pos;
+		let len = self
+			.rest()
+			.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+			.unwrap_or(self.rest().len());
+		self.pos += len;
+
+		(len > 0).then(|| &self.text[start..self.pos])
+	}
+
+	fn value(&mut self) -> Option<TomlValue> {
+		if self.eat("\"") {
+			let mut string = String::new();
+			let mut chars = self.rest().char_indices();
+
+			loop {
+				let (i, c) = chars.next()?;
+				match c {
+					'"' => {
+						self.pos += i + 1;
+						return Some(TomlValue::String(string));
+					}
+					'\\' => string.push(match chars.next()?.1 {
+						'"' => '"',
+						'\\' => '\\',
+						'n' => '\n',
+						't' => '\t',
+						_ => return None,
+					}),
+					'\n' => return None,
+					c => string.push(c),
+				}
+			}
+		}
+
+		if self.eat("'") {
+			let end = self.rest().find(['\'', '\n'])?;
+			let string = self.rest()[..end].to_string();
+			self.pos += end;
+
+			return self.eat("'").then_some(TomlValue::String(string));
+		}
+
+		if self.eat("[") {
+			let mut values = Vec::new();
+
+			loop {
+				self.skip(true);
+				if self.eat("]") {
+					return Some(TomlValue::Array(values));
+				}
+
+				values.push(self.value()?);
+
+				self.skip(true);
+				if !self.eat(",") && !self.rest().starts_with(']') {
+					return None;
+				}
+			}
+		}
+
+		if self.eat("true") {
+			return Some(TomlValue::Bool(true));
+		}
+		if self.eat("false") {
+			return Some(TomlValue::Bool(false));
+		}
+
+		let len = self
+			.rest()
+			.find(|c: char| !(c.is_ascii_digit() || c == '+' || c == '-' || c == '_'))
+			.unwrap_or(self.rest().len());
+		let integer = self.rest()[..len].replace('_', "").parse().ok()?;
+		self.pos += len;
+
+		Some(TomlValue::Integer(integer))
+	}
+}
+
+fn string(key: &str, value: TomlValue) -> anyhow::Result<String> {
+	match value {
+		TomlValue::String(s) => Ok(s),
+		value => bail!("'{}' should be a string, not {}", key, value.type_name()),
+	}
+}
+
+impl Manifest {
+	pub fn parse(text: &str) -> anyhow::Result<Self> {
+		let mut manifest = Self::default();
+		let mut parser = Parser { text, pos: 0 };
+
+		loop {
+			parser.skip(true);
+			if parser.rest().is_empty() {
+				return Ok(manifest);
+			}
+
+			let line = parser.line();
+			let error = || anyhow!("line {}: expected 'key = value'", line);
+
+			let key = parser.key().ok_or_else(error)?.to_string();
+			parser.skip(false);
+			if !parser.eat("=") {
+				return Err(error());
+			}
+			parser.skip(false);
+			let value = parser.value().ok_or_else(error)?;
+
+			parser.skip(false);
+			if !(parser.rest().is_empty() || parser.eat("\n") || parser.rest().starts_with('#')) {
+				return Err(error());
+			}
+
+			let with_line = |e: anyhow::Error| e.context(format!("line {}", line));
+			match key.as_str() {
+				"name" => manifest.name = Some(string(&key, value).map_err(with_line)?),
+				"version" => manifest.version = Some(string(&key, value).map_err(with_line)?),
+				"author" => manifest.author = Some(string(&key, value).map_err(with_line)?),
+				"dependencies" => {
+					let TomlValue::Array(values) = value else {
+						return Err(with_line(anyhow!("'dependencies' should be an array")));
+					};
+
+					manifest.dependencies = values
+						.into_iter()
+						.map(|value| string("dependencies", value))
+						.collect::<anyhow::Result<_>>()
+						.map_err(with_line)?;
+				}
+				"priority" => match value {
+					TomlValue::Integer(i) => manifest.priority = i,
+					value => {
+						return Err(with_line(anyhow!(
+							"'priority' should be an integer, not {}",
+							value.type_name()
+						)))
+					}
+				},
+				"enabled" => match value {
+					TomlValue::Bool(b) => manifest.enabled = b,
+					value => {
+						return Err(with_line(anyhow!(
+							"'enabled' should be a boolean, not {}",
+							value.type_name()
+						)))
+					}
+				},
+				_ => return Err(with_line(anyhow!("unknown key '{}'", key))),
+			}
+		}
+	}
+
+	/// Read `plugin.toml` from a plugin's directory, the default manifest is used if it has none.
+	pub fn load(dir: &Path) -> anyhow::Result<Self> {
+		let path = dir.join("plugin.toml");
+
+		match fs::read_to_string(&path) {
+			Ok(text) => Self::parse(&text).with_context(|| format!("{:?}", path)),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(e).with_context(|| format!("{:?}", path)),
+		}
+	}
+}
+
+/// A plugin found in the plugins directory.
+pub struct Plugin {
+	/// Name of the plugin's directory, which other plugins refer to it by.
+	pub name: String,
+	pub dir: PathBuf,
+	pub manifest: Manifest,
+}
+
+impl Plugin {
+	/// Name to use in logs.
+	pub fn display_name(&self) -> &str {
+		self.manifest.name.as_deref().unwrap_or(&self.name)
+	}
+}
+
+/// Order plugins so that each one comes after its dependencies, and otherwise by priority and name.
+/// Plugins that are disabled, or have dependencies that are missing or form a cycle, are logged and left out.
+pub fn load_order(plugins: Vec<Plugin>) -> Vec<Plugin> {
+	let mut pending = HashMap::new();
+	for plugin in plugins {
+		if plugin.manifest.enabled {
+			pending.insert(plugin.name.clone(), plugin);
+		} else {
+			println!("Plugin '{}' is disabled.", plugin.name);
+		}
+	}
+
+	// Leaving out a plugin may leave its dependents with a missing dependency as well.
+	loop {
+		let missing = pending.values().find_map(|plugin| {
+			let dependency = plugin
+				.manifest
+				.dependencies
+				.iter()
+				.find(|dependency| !pending.contains_key(*dependency))?;
+			Some((plugin.name.clone(), dependency.clone()))
+		});
+		let Some((name, dependency)) = missing else {
+			break;
+		};
+
+		eprintln!(
+			"Plugin '{}' depends on '{}', which is missing or disabled, and will not be loaded.",
+			name, dependency
+		);
+		pending.remove(&name);
+	}
+
+	let mut order = Vec::with_capacity(pending.len());
+	let mut loaded = HashSet::new();
+
+	loop {
+		let next = pending
+			.values()
+			.filter(|plugin| {
+				plugin
+					.manifest
+					.dependencies
+					.iter()
+					.all(|dependency| loaded.contains(dependency))
+			})
+			.min_by(|a, b| {
+				b.manifest
+					.priority
+					.cmp(&a.manifest.priority)
+					.then_with(|| a.name.cmp(&b.name))
+			})
+			.map(|plugin| plugin.name.clone());
+		let Some(name) = next else {
+			break;
+		};
+
+		loaded.insert(name.clone());
+		order.extend(pending.remove(&name));
+	}
+
+	if !pending.is_empty() {
+		let mut names = pending.into_keys().collect::<Vec<_>>();
+		names.sort();
+
+		eprintln!(
+			"Plugins '{}' will not be loaded, their dependencies form a cycle.",
+			names.join("', '")
+		);
+	}
+
+	order
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn error(text: &str) -> String {
+		format!("{:#}", Manifest::parse(text).err().unwrap())
+	}
+
+	fn plugin(name: &str, priority: i64, dependencies: &[&str]) -> Plugin {
+		Plugin {
+			name: name.to_string(),
+			dir: PathBuf::from(name),
+			manifest: Manifest {
+				priority,
+				dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+				..Manifest::default()
+			},
+		}
+	}
+
+	fn order(plugins: Vec<Plugin>) -> Vec<String> {
+		load_order(plugins)
+			.into_iter()
+			.map(|plugin| plugin.name)
+			.collect()
+	}
+
+	#[test]
+	fn parse() {
+		let manifest = Manifest::parse(
+			"# A plugin\r\n\
+			name = \"Some \\\"quoted\\\" \\\\ name\" # trailing comment\r\n\
+			version='1.0 \\n'\n\
+			\n\
+			dependencies = [\n\
+				\"core\", # the core\n\
+				'storage',\n\
+			]\n\
+			priority = -1_000\n\
+			enabled = false",
+		)
+		.unwrap();
+
+		assert_eq!(manifest.name.as_deref(), Some("Some \"quoted\" \\ name"));
+		assert_eq!(manifest.version.as_deref(), Some("1.0 \\n"));
+		assert_eq!(manifest.author, None);
+		assert_eq!(manifest.dependencies, ["core", "storage"]);
+		assert_eq!(manifest.priority, -1000);
+		assert!(!manifest.enabled);
+
+		let manifest = Manifest::parse("").unwrap();
+		assert!(manifest.dependencies.is_empty() && manifest.priority == 0 && manifest.enabled);
+	}
+
+	#[test]
+	fn parse_errors() {
+		assert_eq!(
+			error("name = \"a\"\nversion"),
+			"line 2: expected 'key = value'"
+		);
+		assert_eq!(
+			error("\n\nname = \"a\" b"),
+			"line 3: expected 'key = value'"
+		);
+		assert_eq!(error("name = \"a\nb\""), "line 1: expected 'key = value'");
+		assert_eq!(error("name = \"\\q\""), "line 1: expected 'key = value'");
+		assert_eq!(error("name = 'a"), "line 1: expected 'key = value'");
+		assert_eq!(
+			error("dependencies = [\"a\" \"b\"]"),
+			"line 1: expected 'key = value'"
+		);
+		assert_eq!(
+			error("dependencies = [\n\"a\",\n"),
+			"line 1: expected 'key = value'"
+		);
+		assert_eq!(error("priority = 1.5"), "line 1: expected 'key = value'");
+		assert_eq!(error("[plugin]"), "line 1: expected 'key = value'");
+
+		assert_eq!(
+			error("\nlicense = \"MIT\""),
+			"line 2: unknown key 'license'"
+		);
+		assert_eq!(
+			error("name = 1"),
+			"line 1: 'name' should be a string, not integer"
+		);
+		assert_eq!(
+			error("priority = \"1\""),
+			"line 1: 'priority' should be an integer, not string"
+		);
+		assert_eq!(
+			error("enabled = 1"),
+			"line 1: 'enabled' should be a boolean, not integer"
+		);
+		assert_eq!(
+			error("dependencies = \"a\""),
+			"line 1: 'dependencies' should be an array"
+		);
+		assert_eq!(
+			error("dependencies = [[\"a\"]]"),
+			"line 1: 'dependencies' should be a string, not array"
+		);
+	}
+
+	#[test]
+	fn order_by_priority() {
+		let plugins = vec![
+			plugin("b", 0, &[]),
+			plugin("c", 10, &[]),
+			plugin("a", 0, &[]),
+		];
+		assert_eq!(order(plugins), ["c", "a", "b"]);
+	}
+
+	#[test]
+	fn order_by_dependencies() {
+		// Dependencies win over priority, but the order is otherwise kept.
+		let plugins = vec![
+			plugin("core", -5, &[]),
+			plugin("admin", 10, &["core"]),
+			plugin("chat", 5, &[]),
+			plugin("ranks", 20, &["admin", "chat"]),
+		];
+		assert_eq!(order(plugins), ["chat", "core", "admin", "ranks"]);
+	}
+
+	#[test]
+	fn left_out() {
+		let mut disabled = plugin("disabled", 0, &[]);
+		disabled.manifest.enabled = false;
+
+		let plugins = vec![
+			plugin("core", 0, &[]),
+			disabled,
+			plugin("needs_disabled", 0, &["disabled"]),
+			plugin("needs_missing", 0, &["missing"]),
+			// Dependents of a plugin that's left out are left out as well.
+			plugin("needs_needs_missing", 0, &["needs_missing"]),
+			plugin("cycle_a", 0, &["cycle_b"]),
+			plugin("cycle_b", 0, &["core", "cycle_a"]),
+			plugin("needs_cycle", 0, &["cycle_a"]),
+			plugin("self", 0, &["self"]),
+		];
+		assert_eq!(order(plugins), ["core"]);
+	}
+}
@@ -0,0 +1,23 @@
+use clap::ValueEnum;
+
+/// How a new master is picked once the Bureau has none.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MasterPolicy {
+	/// The user that joined first.
+	Oldest,
+	/// The user with the lowest id.
+	LowestId,
+	/// Whoever `hook.onMasterElect` returns, falling back to the oldest user.
+	Lua,
+}
+
+impl MasterPolicy {
+	/// Pick a master out of `candidates`, which are sorted oldest first.
+	/// `Lua` picks the oldest here, plugins have to be asked before this.
+	pub fn choose(self, candidates: &[i32]) -> Option<i32> {
+		match self {
+			Self::Oldest | Self::Lua => candidates.first().copied(),
+			Self::LowestId => candidates.iter().min().copied(),
+		}
+	}
+}
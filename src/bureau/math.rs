@@ -55,7 +55,7 @@ impl Sub<&Vector3> for &Vector3 {
 	}
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Mat3 {
 	pub data: [f32; 9],
 }
@@ -64,4 +64,19 @@ impl Mat3 {
 	pub fn new() -> Mat3 {
 		Mat3 { data: [0.0; 9] }
 	}
+
+	pub fn identity() -> Mat3 {
+		Mat3 {
+			data: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0],
+		}
+	}
+
+	/// Create a rotation of `angle` radians around the Y axis.
+	pub fn from_yaw(angle: f32) -> Mat3 {
+		let (sin, cos) = angle.sin_cos();
+
+		Mat3 {
+			data: [cos, 0.0, -sin, 0.0, 1.0, 0.0, sin, 0.0, cos],
+		}
+	}
 }
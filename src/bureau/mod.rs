@@ -1,8 +1,21 @@
 mod bureau;
 pub use bureau::*;
 
+pub mod character;
+pub mod codec;
+pub mod emulated_master;
+pub mod frame_reader;
+pub mod json;
 mod lua_api;
+pub mod manifest;
+pub mod master;
 pub mod math;
+pub mod plugin;
+mod plugin_memory;
 pub mod protocol;
+pub mod send_queue;
+pub mod spatial_grid;
+pub mod storage;
+pub mod timer_wheel;
 pub mod user;
 pub mod user_list;
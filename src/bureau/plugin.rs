@@ -0,0 +1,87 @@
+use std::{
+	path::{Component, Path, PathBuf},
+	sync::atomic::{AtomicUsize, Ordering},
+	time::Duration,
+};
+
+/// Number of reloads requested through SIGHUP, every Bureau reloads when it sees this change.
+static RELOADS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Default)]
+pub struct PluginOptions {
+	/// Give every plugin its own environment with a restricted standard library,
+	/// only allowing files inside of its own directory to be opened.
+	pub safe: bool,
+	/// Bytes of Lua memory to allow for each plugin.
+	pub memory_limit: Option<usize>,
+	/// Longest a single hook may run before its event is aborted.
+	pub budget: Option<Duration>,
+	/// Budget overruns after which a plugin gets disabled.
+	pub strikes: u32,
+}
+
+/// How long the hooks of an event took to run.
+#[derive(Clone, Copy, Default)]
+pub struct HookStats {
+	pub calls: u64,
+	pub total: Duration,
+	pub max: Duration,
+	/// Calls aborted for running past `PluginOptions::budget`.
+	pub overruns: u64,
+}
+
+/// Resolve `path` relative to a plugin's directory, `None` if it would lead outside of it.
+pub fn path_in(dir: &Path, path: &str) -> Option<PathBuf> {
+	let path = Path::new(path);
+	if !path
+		.components()
+		.all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+	{
+		return None;
+	}
+
+	// A symlink could still lead elsewhere, so check where the deepest existing part really is.
+	let joined = dir.join(path);
+	let existing = joined.ancestors().find(|p| p.exists())?;
+	if !existing
+		.canonicalize()
+		.ok()?
+		.starts_with(dir.canonicalize().ok()?)
+	{
+		return None;
+	}
+
+	Some(joined)
+}
+
+/// Name of the plugin a chunk was loaded from, going by its chunk name.
+pub fn name_of(source: &str) -> Option<String> {
+	let path = Path::new(source.trim_start_matches(['=', '@']).trim_matches('"'));
+
+	let mut components = path.components();
+	if components.next()? != Component::Normal("plugins".as_ref()) {
+		return None;
+	}
+	// Plugins required with `plugins/?.lua` are single files.
+	let name = Path::new(components.next()?.as_os_str()).file_stem()?;
+
+	Some(name.to_string_lossy().into_owned())
+}
+
+/// Reload the plugins of every Bureau whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn reload_on_sighup() {
+	extern "C" fn handle(_: libc::c_int) {
+		RELOADS.fetch_add(1, Ordering::Relaxed);
+	}
+
+	// SAFETY: The handler only touches an atomic, which is async-signal-safe.
+	unsafe {
+		libc::signal(libc::SIGHUP, handle as *const () as libc::sighandler_t);
+	}
+}
+
+/// Number of reloads requested so far, see `reload_on_sighup`.
+pub fn reloads() -> usize {
+	RELOADS.load(Ordering::Relaxed)
+}
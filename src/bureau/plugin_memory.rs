@@ -0,0 +1,283 @@
+use std::{
+	cell::{OnceCell, RefCell},
+	collections::HashMap,
+	ffi::{c_int, c_void},
+	mem, ptr,
+};
+
+use mlua::{ffi, FromLuaMulti, Function, IntoLuaMulti, LightUserData, Lua, RegistryKey};
+
+// Not part of mlua's bindings.
+const LUAJIT_MODE_WRAPCFUNC: c_int = 0x10;
+const LUAJIT_MODE_ON: c_int = 0x100;
+
+extern "C-unwind" {
+	fn luaJIT_setmode(state: *mut ffi::lua_State, idx: c_int, mode: c_int) -> c_int;
+}
+
+/// Lua memory used by every plugin. Plugins share one Lua state, so its allocator is wrapped
+/// to count each block towards the plugin whose code allocated it.
+pub struct PluginMemory {
+	limit: Option<usize>,
+	state: RefCell<State>,
+	/// Calls a function after telling the allocator that plugin code is running.
+	trampoline: OnceCell<RegistryKey>,
+}
+
+#[derive(Default)]
+struct State {
+	/// Allocator the state had before `install`.
+	alloc: Option<(ffi::lua_Alloc, *mut c_void)>,
+	/// Plugin whose code is running.
+	current: Option<usize>,
+	/// Allocations may fail, which is only the case while Lua code of the current plugin runs.
+	/// mlua allocates outside of protected calls, and Lua errors can't unwind through Rust functions.
+	limited: bool,
+	plugins: HashMap<String, usize>,
+	used: Vec<usize>,
+	/// Plugin that owns each block, blocks of the Bureau itself aren't in here.
+	owners: HashMap<usize, usize>,
+}
+
+impl PluginMemory {
+	pub fn new(limit: Option<usize>) -> Self {
+		Self {
+			limit,
+			state: RefCell::default(),
+			trampoline: OnceCell::new(),
+		}
+	}
+
+	/// Start counting and limiting the memory of `lua`, which has to be closed before `self` is dropped.
+	pub fn install(&self, lua: &Lua) -> mlua::Result<()> {
+		let enter = unsafe { lua.create_c_function(enter)? };
+		let trampoline: Function = lua
+			.load("local enter = ... return function(f, ...) enter() return f(...) end")
+			.set_name("=trampoline")
+			.call(enter)?;
+		let _ = self.trampoline.set(lua.create_registry_value(trampoline)?);
+
+		let install = unsafe { lua.create_c_function(install)? };
+		install.call(LightUserData(self as *const Self as *mut c_void))
+	}
+
+	/// Call `f` as `plugin`, counting what it allocates towards it.
+	/// Functions that aren't part of a plugin keep counting towards the plugin that called them.
+	pub fn call<'lua, R: FromLuaMulti<'lua>>(
+		&self,
+		lua: &'lua Lua,
+		plugin: Option<&str>,
+		f: Function<'lua>,
+		args: impl IntoLuaMulti<'lua>,
+	) -> mlua::Result<R> {
+		let (Some(plugin), Some(trampoline)) = (plugin, self.trampoline.get()) else {
+			return f.call(args);
+		};
+		let trampoline: Function = lua.registry_value(trampoline)?;
+		let mut args = args.into_lua_multi(lua)?;
+		args.push_front(mlua::Value::Function(f));
+
+		let previous = {
+			let mut state = self.state.borrow_mut();
+			let next = state.plugins.len();
+			let index = *state.plugins.entry(plugin.to_string()).or_insert(next);
+			if index == next {
+				state.used.push(0);
+			}
+
+			(state.current.replace(index), state.limited)
+		};
+
+		let ret = trampoline.call(args);
+		let mut state = self.state.borrow_mut();
+		(state.current, state.limited) = previous;
+
+		ret
+	}
+
+	/// Bytes of Lua memory `plugin` is using.
+	#[cfg(test)]
+	fn used(&self, plugin: &str) -> usize {
+		let state = self.state.borrow();
+		state
+			.plugins
+			.get(plugin)
+			.map_or(0, |&index| state.used[index])
+	}
+}
+
+unsafe fn memory_of(state: *mut ffi::lua_State) -> *const PluginMemory {
+	let mut ud = ptr::null_mut();
+	ffi::lua_getallocf(state, &mut ud);
+	ud as *const PluginMemory
+}
+
+unsafe extern "C-unwind" fn install(state: *mut ffi::lua_State) -> c_int {
+	let memory = ffi::lua_touserdata(state, 1) as *const PluginMemory;
+
+	let mut ud = ptr::null_mut();
+	let alloc = ffi::lua_getallocf(state, &mut ud);
+	(*memory).state.borrow_mut().alloc = Some((alloc, ud));
+	ffi::lua_setallocf(state, allocator, memory as *mut c_void);
+
+	ffi::lua_pushlightuserdata(state, wrap_cfunction as *mut c_void);
+	luaJIT_setmode(state, -1, LUAJIT_MODE_WRAPCFUNC | LUAJIT_MODE_ON);
+
+	0
+}
+
+/// Called by the trampoline, handled by `wrap_cfunction`.
+unsafe extern "C-unwind" fn enter(_: *mut ffi::lua_State) -> c_int {
+	0
+}
+
+/// Every C function outside of the standard library is called through this, which are all Rust functions.
+unsafe extern "C-unwind" fn wrap_cfunction(
+	state: *mut ffi::lua_State,
+	f: ffi::lua_CFunction,
+) -> c_int {
+	let memory = memory_of(state);
+	if ptr::fn_addr_eq(f, enter as ffi::lua_CFunction) {
+		(*memory).state.borrow_mut().limited = true;
+		return 0;
+	}
+
+	// Restored even when the function raises an error.
+	struct Restore(*const PluginMemory, bool);
+	impl Drop for Restore {
+		fn drop(&mut self) {
+			unsafe { (*self.0).state.borrow_mut().limited = self.1 };
+		}
+	}
+
+	let limited = mem::replace(&mut (*memory).state.borrow_mut().limited, false);
+	let _restore = Restore(memory, limited);
+
+	f(state)
+}
+
+unsafe extern "C-unwind" fn allocator(
+	ud: *mut c_void,
+	ptr: *mut c_void,
+	osize: usize,
+	nsize: usize,
+) -> *mut c_void {
+	let memory = &*(ud as *const PluginMemory);
+	let mut state = memory.state.borrow_mut();
+	let (alloc, alloc_ud) = state.alloc.unwrap();
+
+	// Lua 5.1 passes 0 as the old size of new blocks, but be sure not to count anything for them.
+	let old_size = if ptr.is_null() { 0 } else { osize };
+	let owner = if ptr.is_null() {
+		None
+	} else {
+		state.owners.remove(&(ptr as usize))
+	};
+	let new_owner = if nsize == 0 {
+		None
+	} else {
+		state.current.or(owner)
+	};
+
+	// Only growth is refused, Lua can't handle freeing or shrinking a block failing.
+	if let (Some(limit), Some(plugin), true) = (memory.limit, state.current, state.limited) {
+		let freed = if owner == Some(plugin) { old_size } else { 0 };
+		if nsize > old_size && state.used[plugin] - freed + nsize > limit {
+			if let Some(owner) = owner {
+				state.owners.insert(ptr as usize, owner);
+			}
+			return ptr::null_mut();
+		}
+	}
+
+	let new = alloc(alloc_ud, ptr, osize, nsize);
+	if new.is_null() && nsize != 0 {
+		if let Some(owner) = owner {
+			state.owners.insert(ptr as usize, owner);
+		}
+		return new;
+	}
+
+	if let Some(owner) = owner {
+		state.used[owner] -= old_size;
+	}
+	if let Some(new_owner) = new_owner {
+		state.used[new_owner] += nsize;
+		state.owners.insert(new as usize, new_owner);
+	}
+
+	new
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LIMIT: usize = 1024 * 1024;
+	const FILL: &str = "local t = {} for i = 1, 100000 do t[i] = {} end return t";
+
+	fn lua(memory: &PluginMemory) -> Lua {
+		let lua = Lua::new();
+		memory.install(&lua).unwrap();
+		lua
+	}
+
+	fn call<'lua>(
+		memory: &PluginMemory,
+		lua: &'lua Lua,
+		plugin: &str,
+		chunk: &str,
+	) -> mlua::Result<mlua::Value<'lua>> {
+		memory.call(lua, Some(plugin), lua.load(chunk).into_function()?, ())
+	}
+
+	#[test]
+	fn limit_per_plugin() {
+		let memory = PluginMemory::new(Some(LIMIT));
+		let lua = lua(&memory);
+
+		let hog = call(&memory, &lua, "hog", FILL);
+		assert!(matches!(hog, Err(mlua::Error::MemoryError(_))), "{:?}", hog);
+		let hog_used = memory.used("hog");
+		assert!(
+			hog_used <= LIMIT && hog_used > LIMIT - 64 * 1024,
+			"{}",
+			hog_used
+		);
+
+		// Others still get their own memory, and so does the Bureau.
+		let kept = call(&memory, &lua, "small", "return {1, 2, 3}").unwrap();
+		assert!(memory.used("small") > 0);
+		lua.load(FILL).exec().unwrap();
+
+		// Whatever is collected is given back, Lua keeps some things such as interned strings around.
+		drop(kept);
+		lua.gc_collect().unwrap();
+		assert!(memory.used("hog") < 1024);
+		assert!(memory.used("small") < 1024);
+	}
+
+	#[test]
+	fn rust_functions_never_fail() {
+		let memory = PluginMemory::new(Some(LIMIT));
+		let lua = lua(&memory);
+		let fill = lua
+			.create_function(|lua, ()| lua.create_sequence_from((0..100000).map(|_| 0.5)))
+			.unwrap();
+		let error = lua
+			.create_function(|_, ()| Err::<(), _>(mlua::Error::runtime("error")))
+			.unwrap();
+		lua.globals().set("fill", fill).unwrap();
+		lua.globals().set("error", error).unwrap();
+
+		// Rust functions may go past the limit, and errors in them don't lift it for Lua code.
+		let ret = call(
+			&memory,
+			&lua,
+			"hog",
+			"local t = fill() assert(#t == 100000) pcall(error) return {}",
+		);
+		assert!(memory.used("hog") > LIMIT);
+		assert!(matches!(ret, Err(mlua::Error::MemoryError(_))), "{:?}", ret);
+	}
+}
@@ -1,23 +1,25 @@
-use super::math::Vector3;
+use std::fmt;
 
 // Documentation of types listed here should be found in VSCP.md.
 
 #[repr(u32)]
+#[derive(Clone, Copy)]
 pub enum Opcode {
-	// CMsgNewUser = 0,
+	CMsgNewUser = 0,
 	SMsgClientId = 1,
 	SMsgUserJoined = 2,
 	SMsgUserLeft = 3,
 	SMsgBroadcastId = 4,
 
 	MsgCommon = 6,
-	// CMsgStateChange = 7,
+	CMsgStateChange = 7,
 	SMsgSetMaster = 8,
 
 	SMsgUserCount = 11,
 }
 
 #[repr(u32)]
+#[derive(Clone, Copy)]
 pub enum MsgCommon {
 	TransformUpdate = 2,
 	ChatSend = 9,
@@ -25,21 +27,24 @@ pub enum MsgCommon {
 	NameChange = 13,
 	AvatarChange = 14,
 	PrivateChat = 15,
+	VcRegister = 16,
+	VoiceState = 18,
+	Unknown19 = 19,
 	ApplSpecific = 10000,
 }
 
-#[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum Strategy {
-	AuraClients = 0,
-	AuraClientsExceptSender = 1,
-	SpecificClient = 2,
-	AllClients = 3,
-	AllClientsExceptSender = 4,
-	Unknown5 = 5,
-	Unknown6 = 6,
+	AuraClients,
+	AuraClientsExceptSender,
+	SpecificClient,
+	AllClients,
+	AllClientsExceptSender,
+	Unknown5,
+	Unknown6,
 
-	Invalid = u8::MAX,
+	/// Any other value, kept so it can be sent on as it was.
+	Other(u8),
 }
 
 impl From<u8> for Strategy {
@@ -52,61 +57,220 @@ impl From<u8> for Strategy {
 			4 => Strategy::AllClientsExceptSender,
 			5 => Strategy::Unknown5,
 			6 => Strategy::Unknown6,
-			_ => Strategy::Invalid,
+			_ => Strategy::Other(value),
 		}
 	}
 }
 
-pub trait ByteReader {
-	fn read_string(&self, start: usize) -> String;
-	fn read_f32(&self, start: usize) -> f32;
-	fn read_u32(&self, start: usize) -> u32;
-	fn read_i32(&self, start: usize) -> i32;
+impl From<Strategy> for u8 {
+	fn from(strategy: Strategy) -> Self {
+		match strategy {
+			Strategy::AuraClients => 0,
+			Strategy::AuraClientsExceptSender => 1,
+			Strategy::SpecificClient => 2,
+			Strategy::AllClients => 3,
+			Strategy::AllClientsExceptSender => 4,
+			Strategy::Unknown5 => 5,
+			Strategy::Unknown6 => 6,
+			Strategy::Other(value) => value,
+		}
+	}
 }
 
-impl ByteReader for [u8] {
-	fn read_string(&self, start: usize) -> String {
-		let mut buf = vec![0; self.len().saturating_sub(start)];
-		let mut i = 0;
-		for b in &self[start..] {
-			let b = *b;
-			if b == 0 {
-				break;
+/// State a client reports through CMsgStateChange.
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UserState {
+	NotConnected = 0,
+	Connecting = 1,
+	Connected = 2,
+	Disconnected = 3,
+	Active = 4,
+	Sleep = 5,
+
+	Invalid = u8::MAX,
+}
+
+impl UserState {
+	pub fn name(self) -> &'static str {
+		match self {
+			UserState::NotConnected => "NotConnected",
+			UserState::Connecting => "Connecting",
+			UserState::Connected => "Connected",
+			UserState::Disconnected => "Disconnected",
+			UserState::Active => "Active",
+			UserState::Sleep => "Sleep",
+			UserState::Invalid => "Invalid",
+		}
+	}
+}
+
+impl From<u8> for UserState {
+	fn from(value: u8) -> Self {
+		match value {
+			0 => UserState::NotConnected,
+			1 => UserState::Connecting,
+			2 => UserState::Connected,
+			3 => UserState::Disconnected,
+			4 => UserState::Active,
+			5 => UserState::Sleep,
+			_ => UserState::Invalid,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+	/// Needed `needed` bytes at `offset`, but only `remaining` were left.
+	Truncated {
+		offset: usize,
+		needed: usize,
+		remaining: usize,
+	},
+	/// A string starting at `offset` ran until the end of the data without a null.
+	UnterminatedString { offset: usize },
+	/// A string starting at `offset` isn't valid UTF-8.
+	InvalidUtf8 { offset: usize },
+	/// A fixed size value was followed by `remaining` unexpected bytes at `offset`.
+	TrailingBytes { offset: usize, remaining: usize },
+	/// A General Message declared a different content size than it had.
+	SizeMismatch { declared: u32, actual: usize },
+	/// The section type byte isn't one of the known types.
+	UnknownSectionType(u8),
+	/// A General Message declared more content than is allowed.
+	TooLarge(u32),
+}
+
+impl fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Truncated {
+				offset,
+				needed,
+				remaining,
+			} => write!(
+				f,
+				"needed {} bytes at offset {} but only {} were left",
+				needed, offset, remaining
+			),
+			Self::UnterminatedString { offset } => {
+				write!(f, "string at offset {} is not null terminated", offset)
+			}
+			Self::InvalidUtf8 { offset } => {
+				write!(f, "string at offset {} is not valid UTF-8", offset)
+			}
+			Self::TrailingBytes { offset, remaining } => {
+				write!(f, "{} unexpected bytes at offset {}", remaining, offset)
 			}
-			buf[i] = b;
-			i += 1;
+			Self::SizeMismatch { declared, actual } => write!(
+				f,
+				"content size was declared as {} but was {}",
+				declared, actual
+			),
+			Self::UnknownSectionType(section_type) => {
+				write!(f, "unknown section type {}", section_type)
+			}
+			Self::TooLarge(size) => write!(f, "content size of {} is too large", size),
+		}
+	}
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Cursor for reading values from a byte slice received from the network.
+/// All functions are Big Endian, and return an error instead of reading past the end.
+pub struct ByteReader<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+	pub fn new(bytes: &'a [u8]) -> Self {
+		Self { bytes, pos: 0 }
+	}
+
+	/// Offset of the next byte to be read.
+	pub fn position(&self) -> usize {
+		self.pos
+	}
+
+	/// Number of bytes left to read.
+	pub fn remaining(&self) -> usize {
+		self.bytes.len() - self.pos
+	}
+
+	/// Read the next `n` bytes.
+	pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+		if self.remaining() < n {
+			return Err(DecodeError::Truncated {
+				offset: self.pos,
+				needed: n,
+				remaining: self.remaining(),
+			});
 		}
 
-		String::from_utf8(buf[..i].to_vec()).unwrap_or_default()
+		let bytes = &self.bytes[self.pos..self.pos + n];
+		self.pos += n;
+
+		Ok(bytes)
 	}
 
-	fn read_f32(&self, start: usize) -> f32 {
-		let n = i32::from_be_bytes([
-			self[start],
-			self[start + 1],
-			self[start + 2],
-			self[start + 3],
-		]);
+	/// Read every byte that hasn't been read yet.
+	pub fn read_rest(&mut self) -> &'a [u8] {
+		let bytes = &self.bytes[self.pos..];
+		self.pos = self.bytes.len();
 
-		(n as f32) / 65535.0
+		bytes
 	}
 
-	fn read_u32(&self, start: usize) -> u32 {
-		u32::from_be_bytes([
-			self[start],
-			self[start + 1],
-			self[start + 2],
-			self[start + 3],
-		])
+	/// Make sure every byte has been read.
+	pub fn finish(&self) -> Result<(), DecodeError> {
+		if self.remaining() > 0 {
+			return Err(DecodeError::TrailingBytes {
+				offset: self.pos,
+				remaining: self.remaining(),
+			});
+		}
+
+		Ok(())
 	}
 
-	fn read_i32(&self, start: usize) -> i32 {
-		i32::from_be_bytes([
-			self[start],
-			self[start + 1],
-			self[start + 2],
-			self[start + 3],
-		])
+	pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+		let mut arr = [0; N];
+		arr.copy_from_slice(self.read_bytes(N)?);
+
+		Ok(arr)
+	}
+
+	pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+		Ok(self.read_array::<1>()?[0])
+	}
+
+	pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+		Ok(u32::from_be_bytes(self.read_array()?))
+	}
+
+	pub fn read_i32(&mut self) -> Result<i32, DecodeError> {
+		Ok(i32::from_be_bytes(self.read_array()?))
+	}
+
+	/// Read an int32float.
+	pub fn read_f32(&mut self) -> Result<f32, DecodeError> {
+		Ok((self.read_i32()? as f64 / 65535.0) as f32)
+	}
+
+	/// Read a string that's terminated by null, the null is consumed but not returned.
+	pub fn read_string(&mut self) -> Result<String, DecodeError> {
+		let offset = self.pos;
+		let Some(len) = self.bytes[offset..].iter().position(|b| *b == 0) else {
+			return Err(DecodeError::UnterminatedString { offset });
+		};
+
+		let s = String::from_utf8(self.bytes[offset..offset + len].to_vec())
+			.map_err(|_| DecodeError::InvalidUtf8 { offset })?;
+		self.pos += len + 1;
+
+		Ok(s)
 	}
 }
 
@@ -123,52 +287,9 @@ impl ByteWriter {
 		}
 	}
 
-	pub fn general_message(id1: i32, id2: i32, opcode: Opcode, content: &[u8]) -> Self {
-		Self::new(17 + content.len())
-			.write_u8(0)
-			.write_i32(id1)
-			.write_i32(id2)
-			.write_u32(opcode as u32)
-			.write_u32(content.len() as u32)
-			.write_arr(content)
-	}
-
-	pub fn position_update(id: i32, pos: &Vector3) -> Self {
-		Self::new(27)
-			.write_u8(2)
-			.write_i32(id)
-			.write_i32(id)
-			.write_i32(id)
-			.write_f32(pos.x)
-			.write_f32(pos.y)
-			.write_f32(pos.z)
-			// ???
-			.write_u8(1)
-			.write_u8(0)
-	}
-
-	pub fn message_common(
-		id1: i32,
-		id2: i32,
-		msg_type: MsgCommon,
-		strategy: Strategy,
-		content: &[u8],
-	) -> Self {
-		Self::general_message(
-			id1,
-			id1,
-			Opcode::MsgCommon,
-			&Self::new(9 + content.len())
-				.write_i32(id2)
-				.write_u32(msg_type as u32)
-				.write_u8(strategy as u8)
-				.write_arr(content)
-				.bytes,
-		)
-	}
-
+	/// Write an int32float, rounded to the closest value it can hold.
 	pub fn write_f32(self, n: f32) -> Self {
-		self.write_i32((n * (0xFFFF as f32)) as i32)
+		self.write_i32((n as f64 * 65535.0).round() as i32)
 	}
 
 	pub fn write_i32(mut self, n: i32) -> Self {
@@ -0,0 +1,216 @@
+use std::{
+	collections::{HashMap, VecDeque},
+	io::{self, ErrorKind, Write},
+	time::{Duration, Instant},
+};
+
+/// How long a queue may go without anything being written before the User is considered stalled.
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy)]
+pub struct SendLimits {
+	/// Queued bytes past which low priority messages start getting coalesced.
+	pub high_water: usize,
+	/// Queued bytes a User may never go over, it's considered stalled right away.
+	pub hard_limit: usize,
+}
+
+/// Low priority messages where only the latest one from each sender matters.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Coalesce {
+	Position(i32),
+	Transform(i32),
+}
+
+/// Outbound buffer for a non-blocking socket.
+pub struct SendQueue {
+	buf: VecDeque<u8>,
+	coalesced: HashMap<Coalesce, Vec<u8>>,
+	limits: SendLimits,
+	/// When something was last written, or when the queue stopped being empty.
+	last_progress: Instant,
+	/// A message was dropped for going over the hard limit.
+	overflowed: bool,
+}
+
+impl SendQueue {
+	pub fn new(limits: SendLimits) -> Self {
+		Self {
+			buf: VecDeque::new(),
+			coalesced: HashMap::new(),
+			limits,
+			last_progress: Instant::now(),
+			overflowed: false,
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.buf.is_empty() && self.coalesced.is_empty()
+	}
+
+	/// Returns true if the queue went over its hard limit,
+	/// or nothing has been written for too long while there's something to write.
+	pub fn is_stalled(&self) -> bool {
+		self.overflowed || (!self.is_empty() && self.last_progress.elapsed() > STALL_TIMEOUT)
+	}
+
+	/// Queue a message that must be delivered.
+	pub fn push(&mut self, bytes: &[u8]) {
+		// Coalesced messages were queued first and must not arrive after this one,
+		// or a stale position could bring back a User that has since left.
+		for (_, coalesced) in self.coalesced.drain() {
+			self.buf.extend(coalesced);
+		}
+
+		if self.overflowed || self.buf.len() + bytes.len() > self.limits.hard_limit {
+			self.overflowed = true;
+			return;
+		}
+
+		if self.buf.is_empty() {
+			self.last_progress = Instant::now();
+		}
+		self.buf.extend(bytes);
+	}
+
+	/// Queue a message that may be replaced by a later message with the same key
+	/// while the queue is past its high-water mark.
+	pub fn push_coalesced(&mut self, key: Coalesce, bytes: &[u8]) {
+		if self.buf.len() < self.limits.high_water && self.coalesced.is_empty() {
+			self.push(bytes);
+			return;
+		}
+
+		if self.is_empty() {
+			self.last_progress = Instant::now();
+		}
+		let latest = self.coalesced.entry(key).or_default();
+		latest.clear();
+		latest.extend_from_slice(bytes);
+	}
+
+	/// Write as much of the queue as `writer` will take without blocking.
+	pub fn flush<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+		loop {
+			if self.buf.len() < self.limits.high_water {
+				for (_, bytes) in self.coalesced.drain() {
+					self.buf.extend(bytes);
+				}
+			}
+
+			if self.buf.is_empty() {
+				break;
+			}
+
+			let (front, _) = self.buf.as_slices();
+			match writer.write(front) {
+				Ok(0) => return Err(ErrorKind::WriteZero.into()),
+				Ok(n) => {
+					self.buf.drain(..n);
+					self.last_progress = Instant::now();
+				}
+				Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(e),
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Takes up to `room` bytes and then blocks.
+	struct Socket {
+		written: Vec<u8>,
+		room: usize,
+	}
+
+	impl Write for Socket {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let n = buf.len().min(self.room);
+			if n == 0 {
+				return Err(ErrorKind::WouldBlock.into());
+			}
+
+			self.room -= n;
+			self.written.extend_from_slice(&buf[..n]);
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn queue(high_water: usize, hard_limit: usize) -> SendQueue {
+		SendQueue::new(SendLimits {
+			high_water,
+			hard_limit,
+		})
+	}
+
+	fn drain(queue: &mut SendQueue) -> Vec<u8> {
+		let mut socket = Socket {
+			written: Vec::new(),
+			room: usize::MAX,
+		};
+		queue.flush(&mut socket).unwrap();
+		socket.written
+	}
+
+	#[test]
+	fn coalesces_past_high_water() {
+		let mut queue = queue(4, 100);
+		queue.push(b"abcd");
+		queue.push_coalesced(Coalesce::Position(1), b"1");
+		queue.push_coalesced(Coalesce::Position(1), b"2");
+		queue.push_coalesced(Coalesce::Position(1), b"3");
+
+		assert_eq!(drain(&mut queue), b"abcd3");
+		assert!(queue.is_empty());
+	}
+
+	#[test]
+	fn coalesced_messages_stay_before_later_messages() {
+		let mut queue = queue(4, 100);
+		queue.push(b"abcd");
+		queue.push_coalesced(Coalesce::Position(1), b"1");
+		queue.push(b"left");
+		queue.push_coalesced(Coalesce::Position(1), b"2");
+
+		assert_eq!(drain(&mut queue), b"abcd1left2");
+	}
+
+	#[test]
+	fn partial_writes_keep_order() {
+		let mut queue = queue(4, 100);
+		queue.push(b"abcdef");
+		queue.push_coalesced(Coalesce::Transform(2), b"t");
+
+		let mut socket = Socket {
+			written: Vec::new(),
+			room: 3,
+		};
+		queue.flush(&mut socket).unwrap();
+		queue.push(b"gh");
+		socket.room = usize::MAX;
+		queue.flush(&mut socket).unwrap();
+
+		assert_eq!(socket.written, b"abcdeftgh");
+	}
+
+	#[test]
+	fn hard_limit_stalls_right_away() {
+		let mut queue = queue(4, 8);
+		queue.push(b"abcdef");
+		assert!(!queue.is_stalled());
+
+		queue.push(b"ghi");
+		assert!(queue.is_stalled());
+		assert_eq!(drain(&mut queue), b"abcdef");
+	}
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use super::math::Vector3;
+
+/// Cells only span X and Z, worlds are mostly flat so splitting by height would just mean more lookups.
+type Cell = (i32, i32);
+
+/// Uniform spatial hash of user positions, with cells as large as the radius that gets searched.
+/// Everyone within the radius of a position is in one of the 4 cells its bounding box touches.
+pub struct SpatialGrid {
+	radius: f32,
+	cell_size: f32,
+	cells: HashMap<Cell, Vec<i32>>,
+	users: HashMap<i32, Cell>,
+}
+
+impl SpatialGrid {
+	pub fn new(radius: f32) -> Self {
+		Self {
+			radius,
+			// Zero would put everyone in the same cell at infinity.
+			cell_size: radius.max(1.0),
+			cells: HashMap::new(),
+			users: HashMap::new(),
+		}
+	}
+
+	fn cell(&self, pos: &Vector3) -> Cell {
+		(
+			(pos.x / self.cell_size).floor() as i32,
+			(pos.z / self.cell_size).floor() as i32,
+		)
+	}
+
+	/// Insert `id` at `pos`, or move it there if it's already in the grid.
+	pub fn update(&mut self, id: i32, pos: &Vector3) {
+		let cell = self.cell(pos);
+
+		match self.users.insert(id, cell) {
+			Some(old) if old == cell => return,
+			Some(old) => self.remove_from_cell(id, old),
+			None => (),
+		}
+
+		self.cells.entry(cell).or_default().push(id);
+	}
+
+	pub fn remove(&mut self, id: i32) {
+		if let Some(cell) = self.users.remove(&id) {
+			self.remove_from_cell(id, cell);
+		}
+	}
+
+	fn remove_from_cell(&mut self, id: i32, cell: Cell) {
+		let Some(ids) = self.cells.get_mut(&cell) else {
+			return;
+		};
+
+		if let Some(i) = ids.iter().position(|other| *other == id) {
+			ids.swap_remove(i);
+		}
+		if ids.is_empty() {
+			self.cells.remove(&cell);
+		}
+	}
+
+	/// Get every id that could be within the radius of `pos`.
+	pub fn nearby(&self, pos: &Vector3) -> Vec<i32> {
+		let r = Vector3::new(self.radius, 0.0, self.radius);
+		let min = self.cell(&(pos - &r));
+		let max = self.cell(&(pos + &r));
+
+		let mut ids = Vec::new();
+		for x in min.0..=max.0 {
+			for z in min.1..=max.1 {
+				if let Some(cell_ids) = self.cells.get(&(x, z)) {
+					ids.extend_from_slice(cell_ids);
+				}
+			}
+		}
+
+		ids
+	}
+}
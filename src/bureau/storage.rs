@@ -0,0 +1,132 @@
+use std::{
+	collections::BTreeMap,
+	fs::{self, File},
+	io::{ErrorKind, Write},
+	path::{Path, PathBuf},
+	sync::Mutex,
+};
+
+use anyhow::{anyhow, Context};
+
+use super::json::Json;
+
+/// Directory with a `<plugin>.json` file for every plugin that stored something.
+const STORAGE_DIR: &str = "storage";
+
+/// Values stored by each plugin, shared by every Bureau in the process so they don't overwrite each others files.
+static STORES: Mutex<BTreeMap<String, Store>> = Mutex::new(BTreeMap::new());
+static FLUSHING: Mutex<()> = Mutex::new(());
+
+struct Store {
+	values: BTreeMap<String, Json>,
+	/// Changed since it was last written to disk.
+	dirty: bool,
+	/// The file couldn't be read, so it's left alone rather than replaced.
+	broken: bool,
+}
+
+fn path_of(plugin: &str) -> PathBuf {
+	Path::new(STORAGE_DIR).join(format!("{}.json", plugin))
+}
+
+fn load(plugin: &str) -> anyhow::Result<BTreeMap<String, Json>> {
+	let path = path_of(plugin);
+
+	let text = match fs::read_to_string(&path) {
+		Ok(text) => text,
+		Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+		Err(e) => return Err(e).with_context(|| format!("{:?}", path)),
+	};
+
+	match Json::parse(&text) {
+		Some(Json::Object(values)) => Ok(values),
+		_ => Err(anyhow!("{:?} is not a JSON object", path)),
+	}
+}
+
+fn with_store<R>(plugin: &str, f: impl FnOnce(&mut Store) -> R) -> R {
+	let mut stores = STORES.lock().unwrap_or_else(|e| e.into_inner());
+
+	let store = stores.entry(plugin.to_string()).or_insert_with(|| {
+		let (values, broken) = match load(plugin) {
+			Ok(values) => (values, false),
+			Err(e) => {
+				eprintln!(
+					"Failed to load storage of plugin '{}', changes to it won't be saved: {:#}",
+					plugin, e
+				);
+				(BTreeMap::new(), true)
+			}
+		};
+
+		Store {
+			values,
+			dirty: false,
+			broken,
+		}
+	});
+
+	f(store)
+}
+
+pub fn get(plugin: &str, key: &str) -> Option<Json> {
+	with_store(plugin, |store| store.values.get(key).cloned())
+}
+
+/// Store `value` under `key`, removing the key if it's `None`.
+pub fn set(plugin: &str, key: &str, value: Option<Json>) {
+	with_store(plugin, |store| {
+		let changed = match value {
+			Some(value) => store.values.insert(key.to_string(), value.clone()) != Some(value),
+			None => store.values.remove(key).is_some(),
+		};
+		store.dirty |= changed;
+	});
+}
+
+pub fn keys(plugin: &str) -> Vec<String> {
+	with_store(plugin, |store| store.values.keys().cloned().collect())
+}
+
+/// Write every store that changed to disk.
+/// Files are replaced atomically, so a crash while writing never leaves a partial one behind.
+pub fn flush() {
+	// Files are written without holding `STORES`, this keeps two Bureaus from writing the same one.
+	let _flushing = FLUSHING.lock().unwrap_or_else(|e| e.into_inner());
+
+	let pending = {
+		let mut stores = STORES.lock().unwrap_or_else(|e| e.into_inner());
+
+		stores
+			.iter_mut()
+			.filter(|(_, store)| store.dirty && !store.broken)
+			.map(|(plugin, store)| {
+				store.dirty = false;
+				(
+					plugin.clone(),
+					Json::Object(store.values.clone()).to_string(),
+				)
+			})
+			.collect::<Vec<_>>()
+	};
+
+	for (plugin, text) in pending {
+		if let Err(e) = write_atomic(&path_of(&plugin), text.as_bytes()) {
+			eprintln!("Failed to save storage of plugin '{}': {:#}", plugin, e);
+			with_store(&plugin, |store| store.dirty = true);
+		}
+	}
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+	fs::create_dir_all(STORAGE_DIR).context(STORAGE_DIR)?;
+
+	let tmp = path.with_extension("json.tmp");
+	let mut file = File::create(&tmp).with_context(|| format!("{:?}", tmp))?;
+	file.write_all(data)
+		.and_then(|_| file.sync_all())
+		.with_context(|| format!("{:?}", tmp))?;
+	fs::rename(&tmp, path).with_context(|| format!("{:?}", path))?;
+
+	Ok(())
+}
@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+/// Hashed timer wheel for coarse timeouts.
+/// Deadlines are rounded up to the next tick, so timers never fire early.
+pub struct TimerWheel<T> {
+	slots: Vec<Vec<(Instant, T)>>,
+	resolution: Duration,
+	start: Instant,
+	/// Next tick that hasn't been expired yet.
+	cursor: u64,
+	len: usize,
+}
+
+impl<T> TimerWheel<T> {
+	pub fn new(resolution: Duration, slot_count: usize) -> Self {
+		Self {
+			slots: (0..slot_count.max(1)).map(|_| Vec::new()).collect(),
+			resolution,
+			start: Instant::now(),
+			cursor: 0,
+			len: 0,
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	fn tick_of(&self, time: Instant) -> u64 {
+		let elapsed = time.saturating_duration_since(self.start);
+		(elapsed.as_nanos() / self.resolution.as_nanos()) as u64
+	}
+
+	fn slot_of(&self, tick: u64) -> usize {
+		(tick % self.slots.len() as u64) as usize
+	}
+
+	/// Schedule `value` to be returned by `expire` once `deadline` has passed.
+	pub fn insert(&mut self, deadline: Instant, value: T) {
+		// Round up so a deadline in the middle of a tick isn't expired early.
+		let tick = (self.tick_of(deadline) + 1).max(self.cursor);
+		let slot = self.slot_of(tick);
+
+		self.slots[slot].push((deadline, value));
+		self.len += 1;
+	}
+
+	/// Schedule `value` to be returned by `expire` after `timeout` has elapsed.
+	pub fn insert_after(&mut self, timeout: Duration, value: T) {
+		self.insert(Instant::now() + timeout, value);
+	}
+
+	/// Get the time at which the next non-empty slot will be checked.
+	/// This may be earlier than the actual deadline of any timer in that slot.
+	pub fn next_deadline(&self) -> Option<Instant> {
+		if self.is_empty() {
+			return None;
+		}
+
+		let slot_count = self.slots.len() as u64;
+		(self.cursor..self.cursor + slot_count)
+			.find(|tick| !self.slots[self.slot_of(*tick)].is_empty())
+			.and_then(|tick| self.time_of(tick))
+	}
+
+	/// Get the time at which `tick` starts, `None` if it's too far away to represent.
+	fn time_of(&self, tick: u64) -> Option<Instant> {
+		let nanos = u64::try_from(self.resolution.as_nanos())
+			.ok()?
+			.checked_mul(tick)?;
+		self.start.checked_add(Duration::from_nanos(nanos))
+	}
+
+	/// Remove and return every timer whose deadline is at or before `now`.
+	pub fn expire(&mut self, now: Instant) -> Vec<T> {
+		let mut expired = Vec::new();
+		if self.is_empty() {
+			self.cursor = self.cursor.max(self.tick_of(now));
+			return expired;
+		}
+
+		let now_tick = self.tick_of(now);
+		// Every slot only needs to be looked at once, no matter how far behind the cursor is.
+		let last_tick = now_tick.min(self.cursor + self.slots.len() as u64 - 1);
+
+		for tick in self.cursor..=last_tick {
+			let slot = self.slot_of(tick);
+
+			let mut i = 0;
+			while i < self.slots[slot].len() {
+				if self.slots[slot][i].0 <= now {
+					expired.push(self.slots[slot].swap_remove(i).1);
+				} else {
+					i += 1;
+				}
+			}
+		}
+
+		self.len -= expired.len();
+		self.cursor = self.cursor.max(now_tick);
+
+		expired
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn expires_at_next_tick() {
+		let mut wheel = TimerWheel::new(Duration::from_millis(10), 8);
+		let deadline = wheel.start + Duration::from_millis(25);
+		wheel.insert(deadline, 1);
+
+		let next = wheel.next_deadline().unwrap();
+		assert!(next >= deadline && next <= deadline + Duration::from_millis(10));
+		assert!(wheel.expire(deadline - Duration::from_millis(1)).is_empty());
+		assert_eq!(wheel.expire(next), [1]);
+		assert!(wheel.is_empty());
+	}
+
+	#[test]
+	fn far_away_timers_are_found() {
+		// More than 2^32 ticks in, which is about 497 days at 10ms.
+		let resolution = Duration::from_millis(10);
+		let mut wheel = TimerWheel::new(resolution, 8);
+		wheel.cursor = 1 << 33;
+		wheel.insert(wheel.start, 1);
+
+		assert_eq!(
+			wheel.next_deadline(),
+			Some(wheel.start + resolution * (1 << 16) * (1 << 17))
+		);
+	}
+}
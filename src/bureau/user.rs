@@ -1,17 +1,25 @@
 use std::{
 	collections::HashSet,
-	io::{self, ErrorKind, Read, Write},
-	net::{SocketAddr, TcpStream},
+	io::{self, ErrorKind, Read},
+	net::SocketAddr,
+	time::{Duration, Instant},
 };
 
+use mio::{net::TcpStream, Interest, Registry, Token};
+
 use super::{
+	character::CharacterData,
+	codec::{Common, CommonMessage, Frame, GeneralMessage, Message, PositionUpdate},
+	frame_reader::FrameReader,
 	math::{Mat3, Vector3},
-	protocol::{ByteReader, ByteWriter, MsgCommon, Opcode, Strategy},
+	protocol::{ByteWriter, Strategy, UserState},
+	send_queue::{Coalesce, SendLimits, SendQueue},
 };
 
 pub enum UserEvent {
 	NewUser(String, String),
-	StateChange,
+	/// New and old state.
+	StateChange(UserState, UserState),
 	PositionUpdate(Vector3),
 	TransformUpdate(Mat3, Vector3),
 	ChatSend(String),
@@ -19,6 +27,8 @@ pub enum UserEvent {
 	NameChange(String),
 	AvatarChange(String),
 	PrivateChat(i32, String),
+	VcRegister(Strategy, i32, Vec<u8>),
+	VoiceState(Vec<u8>),
 	ApplSpecific(Strategy, i32, String, String, i32),
 }
 
@@ -28,31 +38,82 @@ pub struct User {
 	pub connected: bool,
 	pub username: String,
 	pub avatar: String,
-	pub data: String,
+	/// Last CharacterUpdate that was accepted.
+	pub character: Option<CharacterData>,
+	/// Set when the socket reported readiness and hasn't returned `WouldBlock` since.
+	pub readable: bool,
+	/// Number of messages from this User that couldn't be decoded.
+	pub decode_errors: u32,
+	/// When this User sent its CMsgNewUser, only Users that have are seen by plugins.
+	pub joined_at: Option<Instant>,
+	pub state: UserState,
+	/// Last VcRegister and VoiceState this User sent, passed on to users entering its aura.
+	pub vc_register: Option<Vec<u8>>,
+	pub voice_state: Option<Vec<u8>>,
+	/// Stops VoiceState from being passed on.
+	pub voice_muted: bool,
+	/// When `state` last changed.
+	pub state_since: Instant,
 
 	addr: SocketAddr,
 	socket: TcpStream,
+	frame_reader: FrameReader,
+	send_queue: SendQueue,
 	position: Vector3,
 	rotation: Mat3,
 }
 
 impl User {
-	pub fn new(id: i32, socket: TcpStream) -> io::Result<Self> {
+	pub fn new(id: i32, socket: TcpStream, send_limits: SendLimits) -> io::Result<Self> {
 		Ok(Self {
 			id,
 			aura: HashSet::new(),
 			connected: true,
 			username: String::new(),
 			avatar: String::new(),
-			data: String::new(),
+			character: None,
+			readable: true,
+			decode_errors: 0,
+			joined_at: None,
+			state: UserState::Active,
+			vc_register: None,
+			voice_state: None,
+			voice_muted: false,
+			state_since: Instant::now(),
 
 			addr: socket.peer_addr()?,
 			socket,
+			frame_reader: FrameReader::new(),
+			send_queue: SendQueue::new(send_limits),
 			position: Vector3::new(0.0, 0.0, 0.0),
 			rotation: Mat3::new(),
 		})
 	}
 
+	/// Register this User's socket with `registry` using its id as the token.
+	pub fn register(&mut self, registry: &Registry) -> io::Result<()> {
+		registry.reregister(
+			&mut self.socket,
+			Token(self.id as usize),
+			Interest::READABLE | Interest::WRITABLE,
+		)
+	}
+
+	/// Get the SMsgUserJoined that announces this User to others.
+	pub fn joined(&self) -> Message {
+		Message::SMsgUserJoined {
+			broadcast_id: self.id,
+			unknown: self.id,
+			avatar: self.avatar.clone(),
+			username: self.username.clone(),
+		}
+	}
+
+	/// Check if this User has been asleep for longer than `timeout`.
+	pub fn asleep_for(&self, timeout: Duration) -> bool {
+		self.state == UserState::Sleep && self.state_since.elapsed() > timeout
+	}
+
 	/// Get user SocketAddr.
 	pub fn addr(&self) -> &SocketAddr {
 		&self.addr
@@ -60,7 +121,7 @@ impl User {
 
 	/// Set user position.
 	pub fn set_pos(&mut self, pos: Vector3) {
-		self.send(&ByteWriter::position_update(self.id, &pos));
+		self.send(&PositionUpdate::new(self.id, pos.clone()).encode());
 		self.position = pos;
 	}
 	/// Get user position.
@@ -70,219 +131,213 @@ impl User {
 
 	/// Set user rotation.
 	pub fn set_rot(&mut self, rot: Mat3) {
-		let mut transform_update = ByteWriter::new(48);
-
-		for f in rot.data {
-			transform_update = transform_update.write_f32(f);
-		}
-
-		transform_update = transform_update
-			.write_f32(self.position.x)
-			.write_f32(self.position.y)
-			.write_f32(self.position.z);
-
-		self.send(&ByteWriter::message_common(
-			self.id,
-			self.id,
-			MsgCommon::TransformUpdate,
-			Strategy::AuraClients,
-			&transform_update.bytes,
-		));
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				self.id,
+				Strategy::AuraClients,
+				Common::TransformUpdate {
+					rotation: rot.clone(),
+					position: self.position.clone(),
+				},
+			)
+			.encode(),
+		);
 		self.rotation = rot;
 	}
 
-	/// Send all data within a ByteWriter to the socket this User contains.
+	/// Queue all data within a ByteWriter to be sent to the socket this User contains.
 	pub fn send(&mut self, stream: &ByteWriter) {
-		if self.socket.write_all(&stream.bytes).is_err() {
-			self.connected = false;
-		}
+		self.send_queue.push(&stream.bytes);
 	}
 
-	fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
-		match self.socket.read(buf) {
-			Ok(count) => {
-				if count == 0 {
-					self.connected = false;
-					return None;
-				}
+	/// Queue a low priority message, only the latest message for `key` is kept
+	/// while this User is falling behind.
+	pub fn send_coalesced(&mut self, key: Coalesce, stream: &ByteWriter) {
+		self.send_queue.push_coalesced(key, &stream.bytes);
+	}
 
-				Some(count)
-			}
-			Err(e) if e.kind() == ErrorKind::WouldBlock => None,
-			Err(_) => {
-				self.connected = false;
-				None
-			}
+	/// Write as much queued data to the socket as it will take.
+	/// Disconnects the User if it has stopped reading.
+	pub fn flush(&mut self) {
+		if self.send_queue.is_empty() {
+			return;
 		}
-	}
 
-	/// Poll a single event from this User.
-	pub fn poll(&mut self) -> Option<UserEvent> {
-		let mut buf: [u8; 1] = [0];
-		let n = self.read(&mut buf)?;
-		if n == 0 {
-			return None;
+		if self.send_queue.flush(&mut self.socket).is_err() {
+			self.connected = false;
+		} else if self.send_queue.is_stalled() {
+			eprintln!("User {} stopped reading and will be disconnected.", self.id);
+			self.connected = false;
 		}
+	}
 
-		let packet_type = buf[0];
+	/// Read everything currently available on the socket into the receive buffer.
+	fn fill_recv_buf(&mut self) {
+		let mut buf = [0; 4096];
 
-		match packet_type {
-			0 => self.general_message(),
-			1 => {
-				// I don't know what this type does. I do know its most likely 14 bytes.
-				// To avoid outright disconnecting the user for sending a packet that should be valid,
-				// I'm just going to discard the next 14 bytes and hope it'll still work out.
-				self.read(&mut [0; 14]);
-				None
-			}
-			2 => self.position_update(),
-			_ => {
-				self.connected = false;
-				None
+		// Cap how much is read at once so one User can't starve the others,
+		// `readable` stays set so the Bureau will come back for the rest.
+		for _ in 0..16 {
+			match self.socket.read(&mut buf) {
+				Ok(0) => {
+					self.connected = false;
+					return;
+				}
+				Ok(n) => self.frame_reader.extend(&buf[..n]),
+				Err(e) if e.kind() == ErrorKind::WouldBlock => {
+					self.readable = false;
+					return;
+				}
+				Err(e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(_) => {
+					self.connected = false;
+					return;
+				}
 			}
 		}
 	}
 
-	fn general_message(&mut self) -> Option<UserEvent> {
-		let mut msg_header: [u8; 16] = [0; 16];
-		let n = self.read(&mut msg_header)?;
-		if n < 16 {
-			return None;
-		}
+	/// Poll every event from complete frames this User has sent.
+	pub fn poll(&mut self) -> Vec<UserEvent> {
+		self.fill_recv_buf();
+		self.handle_frames()
+	}
 
-		// let id1 = packet.read_i32(0);
-		// let id2 = packet.read_i32(4);
-		let opcode = msg_header.read_u32(8);
-		let size = msg_header.read_u32(12);
+	/// Handle bytes as if they were read from this User's socket,
+	/// returning the events of every frame they completed.
+	pub fn receive(&mut self, bytes: &[u8]) -> Vec<UserEvent> {
+		self.frame_reader.extend(bytes);
+		self.handle_frames()
+	}
 
-		// Would be a bad idea to dynamically allocate a number of bytes that could be u32::MAX.
-		if size > 1024 {
-			self.connected = false;
-			return None;
-		}
+	fn handle_frames(&mut self) -> Vec<UserEvent> {
+		let mut events = Vec::new();
+		loop {
+			let frame = match self.frame_reader.next_frame() {
+				Ok(Some(frame)) => frame,
+				Ok(None) => break,
+				Err(e) => {
+					eprintln!("User {} sent an unreadable stream: {}.", self.id, e);
+					self.connected = false;
+					break;
+				}
+			};
+
+			// Frames are already split correctly, so a bad one can be skipped.
+			let frame = match Frame::decode(&frame) {
+				Ok(frame) => frame,
+				Err(e) => {
+					self.decode_errors += 1;
+					eprintln!(
+						"User {} sent a malformed message ({} so far): {}.",
+						self.id, self.decode_errors, e
+					);
+					continue;
+				}
+			};
 
-		let mut packet = [0; 1024];
-		let n = self.read(&mut packet[..size as usize])?;
-		if n < size as usize {
-			return None;
+			let event = self.handle_frame(frame);
+			events.extend(event);
 		}
 
-		let event = match opcode {
-			0 => self.cmsg_new_user(&packet),
-			6 => self.msg_common(&packet),
-			7 => self.cmsg_state_change(&packet),
-			_ => None,
-		}?;
+		events
+	}
 
-		Some(event)
+	fn handle_frame(&mut self, frame: Frame) -> Option<UserEvent> {
+		match frame {
+			Frame::GeneralMessage(msg) => self.general_message(msg.message),
+			// I don't know what this type does. I do know its most likely 14 bytes.
+			// To avoid outright disconnecting the user for sending a packet that should be valid,
+			// I'm just going to discard it and hope it'll still work out.
+			Frame::Sys1(_) => None,
+			Frame::PositionUpdate(update) => self.position_update(update),
+		}
 	}
 
-	fn position_update(&mut self) -> Option<UserEvent> {
-		let mut packet: [u8; 26] = [0; 26];
-		let n = self.read(&mut packet)?;
-		if n < 26 {
-			return None;
+	fn general_message(&mut self, message: Message) -> Option<UserEvent> {
+		match message {
+			Message::CMsgNewUser { username, avatar } => self.cmsg_new_user(username, avatar),
+			Message::MsgCommon(msg) => self.msg_common(msg),
+			Message::CMsgStateChange(state) => self.cmsg_state_change(state),
+			_ => None,
 		}
+	}
 
-		self.position.set(
-			packet.read_f32(12),
-			packet.read_f32(16),
-			packet.read_f32(20),
-		);
+	fn position_update(&mut self, update: PositionUpdate) -> Option<UserEvent> {
+		self.position = update.position;
 
 		Some(UserEvent::PositionUpdate(self.position.clone()))
 	}
 
 	/* General Message Receivers */
 
-	fn cmsg_new_user(&mut self, packet: &[u8]) -> Option<UserEvent> {
-		let username = packet.read_string(0);
-		if packet.len() < username.len() + 1 {
-			return None;
-		}
-
-		let avatar = packet.read_string(username.len() + 1);
-
+	fn cmsg_new_user(&mut self, username: String, avatar: String) -> Option<UserEvent> {
 		self.username.clone_from(&username);
 		self.avatar.clone_from(&avatar);
+		self.joined_at.get_or_insert_with(Instant::now);
 
-		self.send(&ByteWriter::general_message(
-			0,
-			self.id,
-			Opcode::SMsgClientId,
-			&self.id.to_be_bytes(),
-		));
-
-		self.send(&ByteWriter::general_message(
-			self.id,
-			self.id,
-			Opcode::SMsgUserJoined,
-			&ByteWriter::new(8)
-				.write_i32(self.id)
-				.write_i32(self.id)
-				.write_string(&self.avatar)
-				.write_string(&self.username)
-				.bytes,
-		));
-
-		self.send(&ByteWriter::general_message(
-			self.id,
-			self.id,
-			Opcode::SMsgBroadcastId,
-			&self.id.to_be_bytes(),
-		));
+		self.send(&GeneralMessage::new(0, self.id, Message::SMsgClientId(self.id)).encode());
+		self.send(&GeneralMessage::new(self.id, self.id, self.joined()).encode());
+		self.send(
+			&GeneralMessage::new(self.id, self.id, Message::SMsgBroadcastId(self.id)).encode(),
+		);
 
 		Some(UserEvent::NewUser(username, avatar))
 	}
 
-	fn msg_common(&mut self, packet: &[u8]) -> Option<UserEvent> {
-		if packet.len() < 10 {
-			return None;
-		}
-
-		let id = packet.read_i32(0);
-		let msg_type = packet.read_u32(4);
-		let strategy = packet[8];
-		let content = &packet[9..];
-
-		match msg_type {
-			2 => self.transform_update(content),
+	fn msg_common(&mut self, msg: CommonMessage) -> Option<UserEvent> {
+		let id = msg.broadcast_id;
 
-			9 => self.chat_send(content),
-
-			12 => self.character_update(content),
-			13 => self.name_change(content),
-			14 => self.avatar_change(content),
-			15 => self.private_chat(id, content),
+		match msg.content {
+			Common::TransformUpdate { rotation, position } => {
+				self.transform_update(rotation, position)
+			}
 
-			10000 => self.appl_specific(id, strategy, content),
+			Common::ChatSend(text) => self.chat_send(text),
+
+			Common::CharacterUpdate(data) => self.character_update(data),
+			Common::NameChange(name) => self.name_change(name),
+			Common::AvatarChange(avatar) => self.avatar_change(avatar),
+			Common::PrivateChat { message, .. } => self.private_chat(id, message),
+
+			Common::VcRegister(data) => Some(UserEvent::VcRegister(msg.strategy, id, data)),
+			Common::VoiceState(data) => Some(UserEvent::VoiceState(data)),
+
+			Common::ApplSpecific {
+				method,
+				strarg,
+				intarg,
+				..
+			} => Some(UserEvent::ApplSpecific(
+				msg.strategy,
+				id,
+				method,
+				strarg,
+				intarg,
+			)),
 
 			_ => None,
 		}
 	}
 
-	fn cmsg_state_change(&self, _packet: &[u8]) -> Option<UserEvent> {
-		Some(UserEvent::StateChange)
-	}
-
-	/* Message Common Receivers */
-
-	fn transform_update(&mut self, content: &[u8]) -> Option<UserEvent> {
-		if content.len() < 48 {
+	fn cmsg_state_change(&mut self, state: u8) -> Option<UserEvent> {
+		let state = UserState::from(state);
+		if state == self.state {
 			return None;
 		}
 
-		let mut mat = Mat3::new();
-		for i in 0..9 {
-			mat.data[i] = content.read_f32(i * 4);
-		}
-		self.rotation = mat;
+		let old = std::mem::replace(&mut self.state, state);
+		self.state_since = Instant::now();
 
-		self.position = Vector3::new(
-			content.read_f32(36),
-			content.read_f32(40),
-			content.read_f32(44),
-		);
+		Some(UserEvent::StateChange(state, old))
+	}
+
+	/* Message Common Receivers */
+
+	fn transform_update(&mut self, rotation: Mat3, position: Vector3) -> Option<UserEvent> {
+		self.rotation = rotation;
+		self.position = position;
 
 		Some(UserEvent::TransformUpdate(
 			self.rotation.clone(),
@@ -290,9 +345,7 @@ impl User {
 		))
 	}
 
-	fn chat_send(&self, content: &[u8]) -> Option<UserEvent> {
-		let text = content.read_string(0);
-
+	fn chat_send(&self, text: String) -> Option<UserEvent> {
 		// Don't send empty messages.
 		let (_, message) = text.split_once(": ")?;
 		if message.is_empty() {
@@ -302,60 +355,74 @@ impl User {
 		Some(UserEvent::ChatSend(message.to_string()))
 	}
 
-	fn character_update(&mut self, content: &[u8]) -> Option<UserEvent> {
-		let character_data = content.read_string(0);
-		self.data.clone_from(&character_data);
-
-		Some(UserEvent::CharacterUpdate(character_data))
+	fn character_update(&mut self, data: String) -> Option<UserEvent> {
+		Some(UserEvent::CharacterUpdate(data))
 	}
 
-	fn name_change(&mut self, content: &[u8]) -> Option<UserEvent> {
-		let name = content.read_string(0);
+	fn name_change(&mut self, name: String) -> Option<UserEvent> {
 		self.username.clone_from(&name);
 
 		Some(UserEvent::NameChange(name))
 	}
 
-	fn avatar_change(&mut self, content: &[u8]) -> Option<UserEvent> {
-		let avatar = content.read_string(0);
+	fn avatar_change(&mut self, avatar: String) -> Option<UserEvent> {
 		self.avatar.clone_from(&avatar);
 
 		Some(UserEvent::AvatarChange(avatar))
 	}
 
-	fn private_chat(&mut self, id: i32, content: &[u8]) -> Option<UserEvent> {
-		if content.len() < 5 {
-			return None;
-		}
-		let text = content.read_string(4);
-
+	fn private_chat(&mut self, id: i32, text: String) -> Option<UserEvent> {
 		Some(UserEvent::PrivateChat(id, text))
 	}
+}
 
-	fn appl_specific(&mut self, id: i32, strategy: u8, content: &[u8]) -> Option<UserEvent> {
-		if content.len() < 7 {
-			return None;
-		}
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-		// Unknown = content.read_u8(0);
-		let method = content.read_string(1);
-		if content.len() < method.len() + 2 {
-			return None;
-		}
+	fn user() -> User {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		let socket = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		socket.set_nonblocking(true).unwrap();
 
-		let strarg = content.read_string(method.len() + 2);
-		if content.len() < method.len() + strarg.len() + 6 {
-			return None;
+		let limits = SendLimits {
+			high_water: 64 * 1024,
+			hard_limit: 1024 * 1024,
+		};
+		User::new(1, TcpStream::from_std(socket), limits).unwrap()
+	}
+
+	/// Feed a fuzz input to a User the way the `frame_stream` fuzz target does.
+	fn receive_fuzz_input(user: &mut User, data: &[u8]) -> Vec<UserEvent> {
+		let (chunk_size, stream) = data.split_first().unwrap();
+		let chunk_size = *chunk_size as usize % 64 + 1;
+
+		stream
+			.chunks(chunk_size)
+			.flat_map(|chunk| user.receive(chunk))
+			.collect()
+	}
+
+	#[test]
+	fn fuzz_regressions() {
+		let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/regressions/frame_stream");
+
+		for entry in std::fs::read_dir(dir).unwrap() {
+			let data = std::fs::read(entry.unwrap().path()).unwrap();
+			receive_fuzz_input(&mut user(), &data);
 		}
+	}
 
-		let intarg = content.read_i32(method.len() + strarg.len() + 3);
+	#[test]
+	fn appl_specific_intarg_overrun() {
+		let data =
+			include_bytes!("../../fuzz/regressions/frame_stream/appl-specific-intarg-overrun");
 
-		Some(UserEvent::ApplSpecific(
-			strategy.into(),
-			id,
-			method,
-			strarg,
-			intarg,
-		))
+		let mut user = user();
+		let events = receive_fuzz_input(&mut user, data);
+
+		assert!(events.is_empty());
+		assert_eq!(user.decode_errors, 1);
+		assert!(user.connected);
 	}
 }
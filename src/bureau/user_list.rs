@@ -1,12 +1,15 @@
 use std::{
 	collections::HashMap,
-	io::Write,
-	net::TcpStream,
 	ops::{Deref, DerefMut},
 };
 
+use mio::{net::TcpStream, Registry};
+
 use super::{
-	protocol::{ByteWriter, Opcode},
+	codec::{GeneralMessage, Message},
+	protocol::ByteWriter,
+	send_queue::SendLimits,
+	spatial_grid::SpatialGrid,
 	user::User,
 };
 
@@ -14,7 +17,9 @@ pub struct UserList {
 	users: HashMap<i32, User>,
 	max_index: i32,
 	user_index: i32,
-	master_index: i32,
+	master: Option<i32>,
+	send_limits: SendLimits,
+	grid: SpatialGrid,
 }
 
 impl Deref for UserList {
@@ -32,12 +37,15 @@ impl DerefMut for UserList {
 }
 
 impl UserList {
-	pub fn new(max: i32) -> Self {
+	/// `aura_radius` is the furthest distance `nearby` has to find Users at.
+	pub fn new(max: i32, aura_radius: f32, send_limits: SendLimits) -> Self {
 		Self {
 			users: HashMap::new(),
 			max_index: max,
 			user_index: 0,
-			master_index: -1,
+			master: None,
+			send_limits,
+			grid: SpatialGrid::new(aura_radius),
 		}
 	}
 
@@ -53,48 +61,93 @@ impl UserList {
 	}
 
 	/// Attempt to create a User from a TcpStream and add it to the list.
+	/// The socket is registered with `registry` under the User's id.
 	/// Returns false if a User cannot be created.
-	pub fn add(&mut self, mut socket: TcpStream) -> bool {
+	pub fn add(&mut self, socket: TcpStream, registry: &Registry) -> bool {
 		let Some(id) = self.next_id() else {
 			return false;
 		};
 
-		let buf = [b"hello\0".as_ref(), &id.to_be_bytes(), &id.to_be_bytes()].concat();
-
-		if let Ok(n) = socket.write(&buf) {
-			if n != buf.len() {
-				return false;
-			}
-		}
-
-		let Ok(user) = User::new(id, socket) else {
+		let Ok(mut user) = User::new(id, socket, self.send_limits) else {
 			return false;
 		};
+		if user.register(registry).is_err() {
+			return false;
+		}
+
+		user.send(&ByteWriter {
+			bytes: [b"hello\0".as_ref(), &id.to_be_bytes(), &id.to_be_bytes()].concat(),
+		});
+
+		self.grid.update(id, user.pos());
 		self.insert(id, user);
 
 		true
 	}
 
-	/// Get the id of the User currently assigned the role of master.
-	pub fn master(&mut self) -> Option<i32> {
-		if self.users.contains_key(&self.master_index) {
-			return Some(self.master_index);
+	/// Update where `id` is in the spatial grid, has to be called whenever its position changes.
+	pub fn moved(&mut self, id: i32) {
+		if let Some(user) = self.users.get(&id) {
+			self.grid.update(id, user.pos());
 		}
+	}
+
+	/// Remove every User that is no longer connected.
+	/// Returns true if any were removed.
+	pub fn remove_disconnected(&mut self) -> bool {
+		let grid = &mut self.grid;
+
+		let mut removed = false;
+		self.users.retain(|id, user| {
+			if !user.connected {
+				grid.remove(*id);
+				removed = true;
+			}
+
+			user.connected
+		});
+
+		removed
+	}
+
+	/// Get the id of the User currently assigned the role of master.
+	pub fn master(&self) -> Option<i32> {
+		self.master
+	}
 
-		if let Some((_, user)) = self.users.iter_mut().next() {
-			self.master_index = user.id;
+	/// Get the ids of every User that could become master, oldest first.
+	pub fn master_candidates(&self) -> Vec<i32> {
+		let mut candidates = self
+			.users
+			.values()
+			.filter(|user| user.connected)
+			.filter_map(|user| Some((user.joined_at?, user.id)))
+			.collect::<Vec<_>>();
+		candidates.sort_unstable();
+
+		candidates.into_iter().map(|(_, id)| id).collect()
+	}
 
-			user.send(&ByteWriter::general_message(
-				user.id,
-				user.id,
-				Opcode::SMsgSetMaster,
-				&[1u8],
-			));
+	/// Make `id` the master, telling the previous master it no longer is.
+	/// Returns the previous master.
+	pub fn set_master(&mut self, id: Option<i32>) -> Option<i32> {
+		let old = std::mem::replace(&mut self.master, id);
+		if old == id {
+			return old;
+		}
 
-			return Some(self.master_index);
+		if let Some(user) = old.and_then(|old| self.users.get_mut(&old)) {
+			user.send(
+				&GeneralMessage::new(user.id, user.id, Message::SMsgSetMaster(false)).encode(),
+			);
+		}
+		if let Some(user) = id.and_then(|id| self.users.get_mut(&id)) {
+			user.send(
+				&GeneralMessage::new(user.id, user.id, Message::SMsgSetMaster(true)).encode(),
+			);
 		}
 
-		None
+		old
 	}
 
 	/// Iterate over all Users in the UserList while keeping a mutable reference to `id`.
@@ -103,12 +156,61 @@ impl UserList {
 		F: Fn(&mut User, &mut User),
 	{
 		let mut user = self.users.remove(&id).unwrap();
-		for (_, other) in self.users.iter_mut() {
+		for other in self.users.values_mut() {
 			f(&mut user, other);
 		}
 		self.users.insert(id, user);
 	}
 
+	/// Get the ids of every User that is in the aura of `id` or could be within the aura radius of it.
+	pub fn nearby(&self, id: i32) -> Vec<i32> {
+		let Some(user) = self.users.get(&id) else {
+			return Vec::new();
+		};
+
+		let mut ids = self.grid.nearby(user.pos());
+		ids.extend(user.aura.iter().copied());
+		ids.sort_unstable();
+		ids.dedup();
+		ids.retain(|other_id| *other_id != id);
+
+		ids
+	}
+
+	/// Update where `id` is in the spatial grid and find who has to leave its aura, being further than
+	/// `leave_radius`, and who can enter it, being within `radius`. Entering Users come with their
+	/// distance, closest first.
+	pub fn aura_changes(
+		&mut self,
+		id: i32,
+		radius: f32,
+		leave_radius: f32,
+	) -> (Vec<i32>, Vec<(f32, i32)>) {
+		self.moved(id);
+
+		let mut leaves = Vec::new();
+		let mut enters = Vec::new();
+		let Some(user) = self.users.get(&id) else {
+			return (leaves, enters);
+		};
+
+		for other_id in self.nearby(id) {
+			let distance = user.pos().distance(self.users[&other_id].pos());
+
+			if user.aura.contains(&other_id) {
+				if distance > leave_radius {
+					leaves.push(other_id);
+				}
+			} else if distance <= radius {
+				enters.push((distance, other_id));
+			}
+		}
+
+		enters.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+		(leaves, enters)
+	}
+
 	/// Iterate over all Users in the aura of `id` while keeping an immutable reference to `id`.
 	pub fn for_aura<F>(&mut self, id: i32, f: F)
 	where
@@ -129,13 +231,18 @@ impl UserList {
 	/// Broadcast the current number of connected Users to all Users.
 	pub fn send_user_count(&mut self) {
 		let count = self.len();
-		for (_, user) in self.iter_mut() {
-			user.send(&ByteWriter::general_message(
-				0,
-				0,
-				Opcode::SMsgUserCount,
-				&ByteWriter::new(5).write_u8(1).write_i32(count as i32).bytes,
-			))
+		for user in self.values_mut() {
+			user.send(
+				&GeneralMessage::new(
+					0,
+					0,
+					Message::SMsgUserCount {
+						unknown: 1,
+						count: count as i32,
+					},
+				)
+				.encode(),
+			)
 		}
 	}
 }
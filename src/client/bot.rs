@@ -0,0 +1,223 @@
+use std::{fs, net::SocketAddr, thread, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::bureau::{
+	math::{Mat3, Vector3},
+	protocol::{Strategy, UserState},
+};
+
+use super::{Client, ClientEvent, APPL_SPECIFIC_SERVER};
+
+pub struct BotOptions {
+	pub addr: SocketAddr,
+	pub username: String,
+	pub avatar: String,
+	/// Number of bots to run the script with at once.
+	pub count: usize,
+	/// Path to a file with one command per line, run before `commands`.
+	pub script: Option<String>,
+	pub commands: Vec<String>,
+}
+
+enum Command {
+	/// Print events for a while.
+	Wait(Duration),
+	Move(Vector3),
+	/// Turn to face `angle` radians around the Y axis.
+	Face(f32),
+	Say(String),
+	Whisper(i32, String),
+	Appl(Strategy, i32, String, String, i32),
+	State(UserState),
+	Character(String),
+	VcRegister(Strategy, i32, Vec<u8>),
+	Voice(Vec<u8>),
+	/// Start the script over.
+	Repeat,
+}
+
+fn parse_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+	if !hex.len().is_multiple_of(2) {
+		bail!("Odd number of hex digits in '{}'.", hex);
+	}
+
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+		.collect()
+}
+
+fn parse_command(line: &str) -> anyhow::Result<Option<Command>> {
+	let line = line.trim();
+	if line.is_empty() || line.starts_with('#') {
+		return Ok(None);
+	}
+
+	let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+	let args: Vec<&str> = rest.split_whitespace().collect();
+
+	let command = match (name, args.as_slice()) {
+		("wait", [secs]) => Command::Wait(Duration::try_from_secs_f32(secs.parse()?)?),
+		("move", [x, y, z]) => Command::Move(Vector3::new(x.parse()?, y.parse()?, z.parse()?)),
+		("face", [degrees]) => Command::Face(degrees.parse::<f32>()?.to_radians()),
+		("say", _) if !rest.is_empty() => Command::Say(rest.to_string()),
+		("pm", [id, ..]) => {
+			let (_, text) = rest.split_once(' ').unwrap_or_default();
+			Command::Whisper(id.parse()?, text.trim().to_string())
+		}
+		("appl", [strategy, receiver, method, strarg, intarg]) => Command::Appl(
+			strategy.parse::<u8>()?.into(),
+			match *receiver {
+				"server" => APPL_SPECIFIC_SERVER,
+				id => id.parse()?,
+			},
+			method.to_string(),
+			strarg.to_string(),
+			intarg.parse()?,
+		),
+		("character", [_, ..]) => Command::Character(rest.to_string()),
+		("vcregister", [strategy, receiver, hex]) => Command::VcRegister(
+			strategy.parse::<u8>()?.into(),
+			receiver.parse()?,
+			parse_hex(hex)?,
+		),
+		("voice", [hex]) => Command::Voice(parse_hex(hex)?),
+		("state", [state]) => Command::State(state.parse::<u8>()?.into()),
+		("repeat", []) => Command::Repeat,
+		_ => bail!("Invalid command '{}'.", line),
+	};
+
+	Ok(Some(command))
+}
+
+fn describe(client: &Client, event: &ClientEvent) -> String {
+	let name = |id: &i32| {
+		client
+			.aura
+			.get(id)
+			.map(|user| user.username.as_str())
+			.unwrap_or("?")
+			.to_string()
+	};
+
+	match event {
+		ClientEvent::Joined(id) => format!("{} ({}) entered aura", id, name(id)),
+		ClientEvent::Left(id) => format!("{} left aura", id),
+		ClientEvent::PositionUpdate(id, pos) => {
+			format!("{} moved to {} {} {}", id, pos.x, pos.y, pos.z)
+		}
+		ClientEvent::TransformUpdate(id, _, pos) => {
+			format!("{} transformed at {} {} {}", id, pos.x, pos.y, pos.z)
+		}
+		ClientEvent::ChatSend(id, text) => format!("chat from {}: {}", id, text),
+		ClientEvent::CharacterUpdate(id, data) => format!("{} updated character: {}", id, data),
+		ClientEvent::NameChange(id, username) => format!("{} renamed to {}", id, username),
+		ClientEvent::AvatarChange(id, avatar) => format!("{} changed avatar to {}", id, avatar),
+		ClientEvent::PrivateChat(id, text) => format!("private chat from {}: {}", id, text),
+		ClientEvent::VcRegister(id, data) => format!("{} registered voice: {:02x?}", id, data),
+		ClientEvent::VoiceState(id, data) => format!("{} voice state: {:02x?}", id, data),
+		ClientEvent::ApplSpecific(strategy, id, method, strarg, intarg) => format!(
+			"appl specific from {} ({}): {}({}, {})",
+			id,
+			u8::from(*strategy),
+			method,
+			strarg,
+			intarg
+		),
+		ClientEvent::SetMaster(master) => format!("master set to {}", master),
+		ClientEvent::UserCount(count) => format!("{} users connected", count),
+	}
+}
+
+fn print_events(client: &Client, events: Vec<ClientEvent>) {
+	for event in events {
+		println!("[{}] {}", client.username, describe(client, &event));
+	}
+}
+
+fn run_bot(options: &BotOptions, username: String, commands: &[Command]) -> anyhow::Result<()> {
+	let mut client = Client::connect(options.addr, &username, &options.avatar)?;
+	println!("[{}] Connected with id {}.", username, client.id);
+
+	let mut i = 0;
+	while let Some(command) = commands.get(i) {
+		i += 1;
+
+		match command {
+			Command::Wait(duration) => {
+				let events = client.wait(*duration)?;
+				print_events(&client, events);
+			}
+			Command::Move(pos) => client.set_pos(pos.clone())?,
+			Command::Face(angle) => {
+				let pos = client.position.clone();
+				client.set_transform(Mat3::from_yaw(*angle), pos)?;
+			}
+			Command::Say(text) => client.chat(text)?,
+			Command::Whisper(id, text) => client.private_chat(*id, text)?,
+			Command::Appl(strategy, receiver, method, strarg, intarg) => {
+				client.appl_specific(*strategy, *receiver, method, strarg, *intarg)?
+			}
+			Command::State(state) => client.set_state(*state)?,
+			Command::Character(data) => client.set_character(data)?,
+			Command::VcRegister(strategy, receiver, data) => {
+				client.vc_register(*strategy, *receiver, data)?
+			}
+			Command::Voice(data) => client.voice_state(data)?,
+			Command::Repeat => i = 0,
+		}
+
+		let events = client.poll(Duration::ZERO)?;
+		print_events(&client, events);
+	}
+
+	Ok(())
+}
+
+/// Connect `options.count` bots and run the script on each of them.
+pub fn run(options: BotOptions) -> anyhow::Result<()> {
+	let mut lines = Vec::new();
+	if let Some(path) = &options.script {
+		let script = fs::read_to_string(path).with_context(|| format!("Reading '{}'", path))?;
+		lines.extend(script.lines().map(str::to_string));
+	}
+	lines.extend(options.commands.iter().cloned());
+
+	let mut commands = Vec::new();
+	for line in &lines {
+		commands.extend(parse_command(line)?);
+	}
+
+	thread::scope(|s| {
+		let handles: Vec<_> = (1..=options.count)
+			.map(|i| {
+				let username = match options.count {
+					1 => options.username.clone(),
+					_ => format!("{}{}", options.username, i),
+				};
+
+				let (options, commands) = (&options, &commands);
+				s.spawn(move || {
+					let result = run_bot(options, username.clone(), commands);
+					if let Err(e) = &result {
+						eprintln!("[{}] Stopped: {}", username, e);
+					}
+
+					result
+				})
+			})
+			.collect();
+
+		let failed = handles
+			.into_iter()
+			.map(|handle| handle.join())
+			.filter(|result| !matches!(result, Ok(Ok(()))))
+			.count();
+
+		match failed {
+			0 => Ok(()),
+			n => Err(anyhow!("{} bots stopped early.", n)),
+		}
+	})
+}
@@ -0,0 +1,426 @@
+use std::{
+	collections::HashMap,
+	io::{ErrorKind, Read, Write},
+	net::{SocketAddr, TcpStream, ToSocketAddrs},
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+
+use crate::bureau::{
+	codec::{Common, CommonMessage, Frame, GeneralMessage, Message, PositionUpdate},
+	frame_reader::FrameReader,
+	math::{Mat3, Vector3},
+	protocol::{ByteWriter, Strategy, UserState},
+};
+
+/// Size of the hello a Bureau responds with.
+const HELLO_RESPONSE_SIZE: usize = 14;
+
+/// Broadcast id ApplSpecific messages use to address the Bureau or master instead of a user.
+pub const APPL_SPECIFIC_SERVER: i32 = -9999;
+
+/// Ask the WLS at `addr` which Bureau to connect to for `wrl`.
+pub fn lookup(addr: SocketAddr, wrl: &str) -> anyhow::Result<SocketAddr> {
+	let mut socket = TcpStream::connect(addr)?;
+	socket.write_all(format!("f,0,{}", wrl).as_bytes())?;
+
+	let mut response = String::new();
+	socket.read_to_string(&mut response)?;
+
+	let fields: Vec<&str> = response.trim_end_matches('\0').split(',').collect();
+	let ["f", "0", host, port] = fields[..] else {
+		bail!("WLS has no Bureau available for '{}'.", wrl);
+	};
+
+	(host, port.parse::<u16>()?)
+		.to_socket_addrs()?
+		.next()
+		.ok_or_else(|| anyhow!("Bureau host '{}' could not be resolved.", host))
+}
+
+pub enum ClientEvent {
+	Joined(i32),
+	Left(i32),
+	PositionUpdate(i32, Vector3),
+	TransformUpdate(i32, Mat3, Vector3),
+	ChatSend(i32, String),
+	CharacterUpdate(i32, String),
+	NameChange(i32, String),
+	AvatarChange(i32, String),
+	PrivateChat(i32, String),
+	VcRegister(i32, Vec<u8>),
+	VoiceState(i32, Vec<u8>),
+	ApplSpecific(Strategy, i32, String, String, i32),
+	SetMaster(bool),
+	UserCount(i32),
+}
+
+/// Another user as seen through this Client's aura.
+pub struct AuraUser {
+	pub username: String,
+	pub avatar: String,
+	pub data: String,
+	pub position: Vector3,
+	pub rotation: Mat3,
+}
+
+/// Headless VSCP client, acts like a Community Place browser connected to a Bureau.
+pub struct Client {
+	pub id: i32,
+	pub username: String,
+	pub avatar: String,
+	pub position: Vector3,
+	pub rotation: Mat3,
+	/// Users that are currently in this Client's aura.
+	pub aura: HashMap<i32, AuraUser>,
+	pub master: bool,
+	pub user_count: i32,
+	/// Number of frames from the Bureau that couldn't be decoded.
+	pub decode_errors: u32,
+
+	socket: TcpStream,
+	frame_reader: FrameReader,
+}
+
+impl Client {
+	/// Connect to a Bureau and join as `username` using `avatar`.
+	pub fn connect(addr: SocketAddr, username: &str, avatar: &str) -> anyhow::Result<Self> {
+		let mut socket = TcpStream::connect(addr)?;
+		socket.set_nodelay(true)?;
+
+		socket.write_all(b"hello\x01\x01")?;
+		let mut hello = [0; HELLO_RESPONSE_SIZE];
+		socket.read_exact(&mut hello)?;
+		if !hello.starts_with(b"hello") {
+			bail!("Bureau responded with an invalid hello.");
+		}
+		let id = i32::from_be_bytes([hello[10], hello[11], hello[12], hello[13]]);
+
+		let mut client = Self {
+			id,
+			username: username.to_string(),
+			avatar: avatar.to_string(),
+			position: Vector3::new(0.0, 0.0, 0.0),
+			rotation: Mat3::identity(),
+			aura: HashMap::new(),
+			master: false,
+			user_count: 0,
+			decode_errors: 0,
+
+			socket,
+			frame_reader: FrameReader::new(),
+		};
+
+		client.send(
+			&GeneralMessage::new(
+				id,
+				id,
+				Message::CMsgNewUser {
+					username: username.to_string(),
+					avatar: avatar.to_string(),
+				},
+			)
+			.encode(),
+		)?;
+
+		Ok(client)
+	}
+
+	/// Send all data within a ByteWriter to the Bureau.
+	pub fn send(&mut self, stream: &ByteWriter) -> anyhow::Result<()> {
+		self.socket.write_all(&stream.bytes)?;
+
+		Ok(())
+	}
+
+	pub fn set_pos(&mut self, pos: Vector3) -> anyhow::Result<()> {
+		self.send(&PositionUpdate::new(self.id, pos.clone()).encode())?;
+		self.position = pos;
+
+		Ok(())
+	}
+
+	pub fn set_transform(&mut self, rotation: Mat3, position: Vector3) -> anyhow::Result<()> {
+		self.rotation = rotation.clone();
+		self.position = position.clone();
+
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				self.id,
+				Strategy::AuraClients,
+				Common::TransformUpdate { rotation, position },
+			)
+			.encode(),
+		)
+	}
+
+	pub fn chat(&mut self, text: &str) -> anyhow::Result<()> {
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				self.id,
+				Strategy::AllClientsExceptSender,
+				Common::ChatSend(format!("{}: {}", self.username, text)),
+			)
+			.encode(),
+		)
+	}
+
+	pub fn private_chat(&mut self, receiver: i32, text: &str) -> anyhow::Result<()> {
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				receiver,
+				Strategy::SpecificClient,
+				Common::PrivateChat {
+					broadcast_id: self.id,
+					message: format!("{}: {}", self.username, text),
+				},
+			)
+			.encode(),
+		)
+	}
+
+	/// Send CharacterUpdate data such as `sleep:0 1:000000000000:58:0:` to the aura.
+	pub fn set_character(&mut self, data: &str) -> anyhow::Result<()> {
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				self.id,
+				Strategy::AuraClientsExceptSender,
+				Common::CharacterUpdate(data.to_string()),
+			)
+			.encode(),
+		)
+	}
+
+	/// Send a VcRegister to `receiver`, its content is passed on as is.
+	pub fn vc_register(
+		&mut self,
+		strategy: Strategy,
+		receiver: i32,
+		data: &[u8],
+	) -> anyhow::Result<()> {
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				receiver,
+				strategy,
+				Common::VcRegister(data.to_vec()),
+			)
+			.encode(),
+		)
+	}
+
+	/// Send a VoiceState to the aura, its content is passed on as is.
+	pub fn voice_state(&mut self, data: &[u8]) -> anyhow::Result<()> {
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				self.id,
+				Strategy::AuraClientsExceptSender,
+				Common::VoiceState(data.to_vec()),
+			)
+			.encode(),
+		)
+	}
+
+	/// Tell the Bureau this Client is now active, asleep, etc.
+	pub fn set_state(&mut self, state: UserState) -> anyhow::Result<()> {
+		self.send(
+			&GeneralMessage::new(self.id, self.id, Message::CMsgStateChange(state as u8)).encode(),
+		)
+	}
+
+	/// Send an ApplSpecific to `receiver`, use `APPL_SPECIFIC_SERVER` to address the Bureau.
+	pub fn appl_specific(
+		&mut self,
+		strategy: Strategy,
+		receiver: i32,
+		method: &str,
+		strarg: &str,
+		intarg: i32,
+	) -> anyhow::Result<()> {
+		self.send(
+			&GeneralMessage::common(
+				self.id,
+				receiver,
+				strategy,
+				Common::ApplSpecific {
+					unknown: 2,
+					method: method.to_string(),
+					strarg: strarg.to_string(),
+					intarg,
+				},
+			)
+			.encode(),
+		)
+	}
+
+	/// Wait up to `timeout` for data from the Bureau, returning every event it contained.
+	pub fn poll(&mut self, timeout: Duration) -> anyhow::Result<Vec<ClientEvent>> {
+		// A zero timeout would block forever.
+		self.socket
+			.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+
+		let mut buf = [0; 4096];
+		match self.socket.read(&mut buf) {
+			Ok(0) => bail!("Bureau closed the connection."),
+			Ok(n) => self.frame_reader.extend(&buf[..n]),
+			Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => (),
+			Err(e) if e.kind() == ErrorKind::Interrupted => (),
+			Err(e) => return Err(e.into()),
+		}
+
+		let mut events = Vec::new();
+		while let Some(frame) = self.frame_reader.next_frame()? {
+			let frame = match Frame::decode(&frame) {
+				Ok(frame) => frame,
+				Err(e) => {
+					self.decode_errors += 1;
+					eprintln!(
+						"Client {} received a malformed frame ({} so far): {}.",
+						self.id, self.decode_errors, e
+					);
+					continue;
+				}
+			};
+
+			events.extend(self.handle_frame(frame));
+		}
+
+		Ok(events)
+	}
+
+	/// Keep polling until `duration` has passed, returning every event received.
+	pub fn wait(&mut self, duration: Duration) -> anyhow::Result<Vec<ClientEvent>> {
+		let deadline = Instant::now() + duration;
+
+		let mut events = Vec::new();
+		loop {
+			let now = Instant::now();
+			if now >= deadline {
+				return Ok(events);
+			}
+
+			events.extend(self.poll(deadline - now)?);
+		}
+	}
+
+	fn handle_frame(&mut self, frame: Frame) -> Option<ClientEvent> {
+		match frame {
+			Frame::GeneralMessage(msg) => self.general_message(msg.message),
+			Frame::Sys1(_) => None,
+			Frame::PositionUpdate(update) => {
+				let id = update.broadcast_id;
+				if let Some(user) = self.aura.get_mut(&id) {
+					user.position = update.position.clone();
+				}
+
+				Some(ClientEvent::PositionUpdate(id, update.position))
+			}
+		}
+	}
+
+	fn general_message(&mut self, message: Message) -> Option<ClientEvent> {
+		match message {
+			Message::SMsgUserJoined {
+				broadcast_id,
+				avatar,
+				username,
+				..
+			} => {
+				// The Bureau announces us to ourselves as well.
+				if broadcast_id == self.id {
+					return None;
+				}
+
+				self.aura.insert(
+					broadcast_id,
+					AuraUser {
+						username,
+						avatar,
+						data: String::new(),
+						position: Vector3::new(0.0, 0.0, 0.0),
+						rotation: Mat3::new(),
+					},
+				);
+
+				Some(ClientEvent::Joined(broadcast_id))
+			}
+			Message::SMsgUserLeft(id) => {
+				self.aura.remove(&id)?;
+
+				Some(ClientEvent::Left(id))
+			}
+			Message::MsgCommon(msg) => self.msg_common(msg),
+			Message::SMsgSetMaster(master) => {
+				self.master = master;
+
+				Some(ClientEvent::SetMaster(master))
+			}
+			Message::SMsgUserCount { count, .. } => {
+				self.user_count = count;
+
+				Some(ClientEvent::UserCount(count))
+			}
+			_ => None,
+		}
+	}
+
+	fn msg_common(&mut self, msg: CommonMessage) -> Option<ClientEvent> {
+		let id = msg.broadcast_id;
+		let user = self.aura.get_mut(&id);
+
+		match msg.content {
+			Common::TransformUpdate { rotation, position } => {
+				if let Some(user) = user {
+					user.rotation = rotation.clone();
+					user.position = position.clone();
+				}
+
+				Some(ClientEvent::TransformUpdate(id, rotation, position))
+			}
+			Common::ChatSend(text) => Some(ClientEvent::ChatSend(id, text)),
+			Common::CharacterUpdate(data) => {
+				if let Some(user) = user {
+					user.data.clone_from(&data);
+				}
+
+				Some(ClientEvent::CharacterUpdate(id, data))
+			}
+			Common::NameChange(name) => {
+				if let Some(user) = user {
+					user.username.clone_from(&name);
+				}
+
+				Some(ClientEvent::NameChange(id, name))
+			}
+			Common::AvatarChange(avatar) => {
+				if let Some(user) = user {
+					user.avatar.clone_from(&avatar);
+				}
+
+				Some(ClientEvent::AvatarChange(id, avatar))
+			}
+			Common::PrivateChat { message, .. } => Some(ClientEvent::PrivateChat(id, message)),
+			Common::VcRegister(data) => Some(ClientEvent::VcRegister(id, data)),
+			Common::VoiceState(data) => Some(ClientEvent::VoiceState(id, data)),
+			Common::ApplSpecific {
+				method,
+				strarg,
+				intarg,
+				..
+			} => Some(ClientEvent::ApplSpecific(
+				msg.strategy,
+				id,
+				method,
+				strarg,
+				intarg,
+			)),
+			_ => None,
+		}
+	}
+}
@@ -0,0 +1,311 @@
+use std::{
+	net::SocketAddr,
+	thread,
+	time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+
+use crate::bureau::{math::Vector3, protocol::Strategy};
+
+use super::{lookup, Client, ClientEvent, APPL_SPECIFIC_SERVER};
+
+/// ApplSpecific method used to carry timestamps between simulated clients.
+const APPL_METHOD: &str = "loadtest";
+/// Chance of a walking client picking a new direction on each step.
+const TURN_CHANCE: f32 = 0.05;
+
+pub struct LoadTestOptions {
+	/// Address of the Bureau, or of the WLS if `wrl` is set.
+	pub addr: SocketAddr,
+	pub wrl: Option<String>,
+	pub clients: usize,
+	/// Clients connected per second while ramping up.
+	pub connect_rate: f32,
+	pub duration: Duration,
+	pub move_interval: Duration,
+	/// Units walked per second.
+	pub speed: f32,
+	/// Clients walk within a square of this size centered on the origin.
+	pub area: f32,
+	/// Chat messages per client per minute.
+	pub chat_rate: f32,
+	/// ApplSpecific messages per client per minute.
+	pub appl_rate: f32,
+}
+
+/// Small xorshift generator, good enough to scatter clients around.
+struct Rng(u64);
+
+impl Rng {
+	fn new(seed: u64) -> Self {
+		Self(seed.wrapping_mul(0x9E3779B97F4A7C15) | 1)
+	}
+
+	fn next_u64(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	/// Random value in `0.0..1.0`.
+	fn next_f32(&mut self) -> f32 {
+		(self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+	}
+}
+
+/// When the next event after `now` of something that happens `per_minute` times a minute on average is,
+/// `None` if it never happens or is too far away to be represented.
+fn next_event(rng: &mut Rng, now: Instant, per_minute: f32) -> Option<Instant> {
+	let mean = 60.0 / per_minute;
+	let interval = Duration::try_from_secs_f32(mean * (0.5 + rng.next_f32())).ok()?;
+
+	now.checked_add(interval)
+}
+
+#[derive(Default)]
+struct Stats {
+	connected: bool,
+	disconnected: bool,
+	sent: u64,
+	received: u64,
+	/// Frames from the Bureau that couldn't be decoded.
+	decode_errors: u32,
+	/// Relay latency of every timestamped message received, in microseconds.
+	latencies: Vec<u64>,
+}
+
+struct SimClient {
+	client: Client,
+	rng: Rng,
+	heading: f32,
+	next_move: Instant,
+	next_chat: Option<Instant>,
+	next_appl: Option<Instant>,
+}
+
+impl SimClient {
+	fn step(&mut self, options: &LoadTestOptions) -> anyhow::Result<()> {
+		if self.rng.next_f32() < TURN_CHANCE {
+			self.heading = self.rng.next_f32() * std::f32::consts::TAU;
+		}
+
+		let distance = options.speed * options.move_interval.as_secs_f32();
+		let half = options.area / 2.0;
+		let pos = &self.client.position;
+
+		let mut x = pos.x + self.heading.cos() * distance;
+		let mut z = pos.z + self.heading.sin() * distance;
+		if x.abs() > half || z.abs() > half {
+			// Turn around at the edge.
+			self.heading += std::f32::consts::PI;
+			x = x.clamp(-half, half);
+			z = z.clamp(-half, half);
+		}
+
+		self.client.set_pos(Vector3::new(x, 0.0, z))
+	}
+}
+
+fn connect(options: &LoadTestOptions, i: usize) -> anyhow::Result<Client> {
+	let addr = match &options.wrl {
+		Some(wrl) => lookup(options.addr, wrl)?,
+		None => options.addr,
+	};
+
+	Client::connect(addr, &format!("Load{}", i), "loadtest.wrl")
+}
+
+fn run_client(options: &LoadTestOptions, i: usize, start: Instant, end: Instant) -> Stats {
+	let mut stats = Stats::default();
+
+	let mut client = match connect(options, i) {
+		Ok(client) => client,
+		Err(e) => {
+			eprintln!("Client {} failed to connect: {}", i, e);
+			return stats;
+		}
+	};
+	stats.connected = true;
+
+	let mut rng = Rng::new(i as u64 + 1);
+	let half = options.area / 2.0;
+	let pos = Vector3::new(
+		(rng.next_f32() - 0.5) * 2.0 * half,
+		0.0,
+		(rng.next_f32() - 0.5) * 2.0 * half,
+	);
+	client.position = pos;
+
+	let now = Instant::now();
+	let mut sim = SimClient {
+		heading: rng.next_f32() * std::f32::consts::TAU,
+		next_move: now,
+		next_chat: next_event(&mut rng, now, options.chat_rate),
+		next_appl: next_event(&mut rng, now, options.appl_rate),
+		client,
+		rng,
+	};
+
+	let result = (|| -> anyhow::Result<()> {
+		loop {
+			let now = Instant::now();
+			if now >= end {
+				return Ok(());
+			}
+
+			if now >= sim.next_move {
+				sim.step(options)?;
+				sim.next_move = now + options.move_interval;
+				stats.sent += 1;
+			}
+
+			let stamp = start.elapsed().as_micros();
+			if sim.next_chat.is_some_and(|at| now >= at) {
+				sim.client.chat(&stamp.to_string())?;
+				sim.next_chat = next_event(&mut sim.rng, now, options.chat_rate);
+				stats.sent += 1;
+			}
+
+			if sim.next_appl.is_some_and(|at| now >= at) {
+				sim.client.appl_specific(
+					Strategy::AllClientsExceptSender,
+					APPL_SPECIFIC_SERVER,
+					APPL_METHOD,
+					&stamp.to_string(),
+					0,
+				)?;
+				sim.next_appl = next_event(&mut sim.rng, now, options.appl_rate);
+				stats.sent += 1;
+			}
+
+			let next = [Some(sim.next_move), sim.next_chat, sim.next_appl, Some(end)]
+				.into_iter()
+				.flatten()
+				.min()
+				.unwrap();
+
+			for event in sim.client.poll(next.saturating_duration_since(now))? {
+				stats.received += 1;
+
+				let stamp = match &event {
+					ClientEvent::ChatSend(_, text) => text.rsplit_once(": ").map(|(_, s)| s),
+					ClientEvent::ApplSpecific(_, _, method, strarg, _) if method == APPL_METHOD => {
+						Some(strarg.as_str())
+					}
+					_ => None,
+				};
+
+				if let Some(Ok(sent)) = stamp.map(str::parse::<u64>) {
+					let received = start.elapsed().as_micros() as u64;
+					stats.latencies.push(received.saturating_sub(sent));
+				}
+			}
+		}
+	})();
+
+	if let Err(e) = result {
+		eprintln!("Client {} was disconnected: {}", i, e);
+		stats.disconnected = true;
+	}
+	stats.decode_errors = sim.client.decode_errors;
+
+	stats
+}
+
+fn percentile(sorted: &[u64], p: f32) -> f32 {
+	if sorted.is_empty() {
+		return 0.0;
+	}
+
+	let i = ((sorted.len() - 1) as f32 * p).round() as usize;
+	sorted[i] as f32 / 1000.0
+}
+
+/// Simulate `options.clients` users and print a report once `options.duration` has passed.
+pub fn run(options: LoadTestOptions) -> anyhow::Result<()> {
+	let start = Instant::now();
+	let ramp = options.clients as f32 / options.connect_rate.max(0.001);
+	let (ramp, end) = Duration::try_from_secs_f32(ramp)
+		.ok()
+		.and_then(|ramp| {
+			Some((
+				ramp,
+				start.checked_add(ramp)?.checked_add(options.duration)?,
+			))
+		})
+		.ok_or_else(|| {
+			anyhow!(
+				"Connecting {} clients at {} per second and running for {:?} takes too long.",
+				options.clients,
+				options.connect_rate,
+				options.duration
+			)
+		})?;
+
+	println!(
+		"Connecting {} clients over {:.1}s, then running for {:.1}s.",
+		options.clients,
+		ramp.as_secs_f32(),
+		options.duration.as_secs_f32()
+	);
+
+	let results: Vec<Stats> = thread::scope(|s| {
+		let handles: Vec<_> = (0..options.clients)
+			.map(|i| {
+				let options = &options;
+				let connect_at =
+					start + Duration::from_secs_f32(i as f32 / options.connect_rate.max(0.001));
+
+				s.spawn(move || {
+					thread::sleep(connect_at.saturating_duration_since(Instant::now()));
+					run_client(options, i, start, end)
+				})
+			})
+			.collect();
+
+		handles
+			.into_iter()
+			.map(|handle| handle.join().unwrap_or_default())
+			.collect()
+	});
+
+	let elapsed = start.elapsed().as_secs_f32();
+	let connected = results.iter().filter(|stats| stats.connected).count();
+	let disconnected = results.iter().filter(|stats| stats.disconnected).count();
+	let sent: u64 = results.iter().map(|stats| stats.sent).sum();
+	let received: u64 = results.iter().map(|stats| stats.received).sum();
+	let decode_errors: u32 = results.iter().map(|stats| stats.decode_errors).sum();
+
+	let mut latencies: Vec<u64> = results
+		.into_iter()
+		.flat_map(|stats| stats.latencies)
+		.collect();
+	latencies.sort_unstable();
+
+	println!(
+		"Clients: {} connected, {} disconnected early, {} failed to connect.",
+		connected,
+		disconnected,
+		options.clients - connected
+	);
+	println!(
+		"Throughput: {:.0} messages/s sent, {:.0} messages/s received.",
+		sent as f32 / elapsed,
+		received as f32 / elapsed
+	);
+	if decode_errors > 0 {
+		println!("Malformed frames received: {}.", decode_errors);
+	}
+	println!(
+		"Relay latency over {} samples: p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms.",
+		latencies.len(),
+		percentile(&latencies, 0.5),
+		percentile(&latencies, 0.9),
+		percentile(&latencies, 0.99),
+		percentile(&latencies, 1.0)
+	);
+
+	Ok(())
+}
@@ -0,0 +1,5 @@
+mod client;
+pub use client::*;
+
+pub mod bot;
+pub mod loadtest;
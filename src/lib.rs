@@ -0,0 +1,3 @@
+pub mod bureau;
+pub mod client;
+pub mod wls;
@@ -1,16 +1,30 @@
-mod bureau;
-mod wls;
-
-use clap::Parser;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use clap::{Parser, Subcommand};
+use std::{
+	net::{IpAddr, Ipv4Addr, SocketAddr},
+	path::Path,
+	time::Duration,
+};
 
-use crate::{
-	bureau::{Bureau, BureauOptions},
-	wls::WlsOptions,
+use spark::{
+	bureau::{
+		emulated_master::EmulatedMaster,
+		master::MasterPolicy,
+		plugin::{self, PluginOptions},
+		send_queue::SendLimits,
+		Bureau, BureauOptions,
+	},
+	client::{
+		bot::{self, BotOptions},
+		loadtest::{self, LoadTestOptions},
+	},
+	wls::{self, WlsOptions},
 };
 
 #[derive(Parser)]
 struct Args {
+	#[command(subcommand)]
+	command: Option<Command>,
+
 	/// If set, program will function in WLS mode.
 	#[arg(short, long)]
 	wls: bool,
@@ -38,16 +52,236 @@ struct Args {
 	/// Radius to add two users to each others aura.
 	#[arg(short, long, default_value_t = 300.0)]
 	aura_radius: f32,
+
+	/// Radius to remove two users from each others aura, set it above the aura radius so that users
+	/// near the edge don't keep entering and leaving [default: aura radius].
+	#[arg(long)]
+	aura_leave_radius: Option<f32>,
+
+	/// Maximum number of users in an aura, the closest ones are kept.
+	#[arg(long)]
+	max_aura: Option<usize>,
+
+	/// How a new master client is picked when the current one leaves.
+	#[arg(long, value_enum, default_value_t = MasterPolicy::Oldest)]
+	master_policy: MasterPolicy,
+
+	/// Seconds a user may stay asleep before being disconnected.
+	#[arg(long)]
+	sleep_timeout: Option<u64>,
+
+	/// Let the Bureau answer requests meant for the master client, from Lua or --master-config.
+	#[arg(long)]
+	emulate_master: bool,
+
+	/// File path to the replies the Bureau answers master requests with, implies --emulate-master.
+	/// In WLS mode, a directory with a '<wrl>.txt' file for every wrl that needs one.
+	#[arg(long)]
+	master_config: Option<String>,
+
+	/// Pass character data that can't be parsed on unchanged instead of dropping it.
+	/// Only the users in the sender's aura at the time get it.
+	#[arg(long)]
+	pass_invalid_characters: bool,
+
+	/// Run every plugin in its own environment with a restricted standard library,
+	/// only letting it open files inside of its own directory.
+	#[arg(long)]
+	safe_plugins: bool,
+
+	/// Megabytes of Lua memory to allow for each plugin.
+	#[arg(long)]
+	plugin_memory: Option<usize>,
+
+	/// Milliseconds a single hook may run before its event is aborted.
+	#[arg(long)]
+	lua_budget: Option<u64>,
+
+	/// Times a plugin may run past --lua-budget before it gets disabled.
+	#[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+	lua_strikes: u32,
+
+	/// Bytes queued for a user past which position and transform updates get coalesced.
+	#[arg(long, default_value_t = 64 * 1024)]
+	send_high_water: usize,
+
+	/// Bytes queued for a user past which it's disconnected right away.
+	#[arg(long, default_value_t = 1024 * 1024)]
+	send_hard_limit: usize,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Connect scripted bots to a Bureau instead of running a server.
+	///
+	/// Commands are `wait <secs>`, `move <x> <y> <z>`, `face <degrees>`, `say <text>`,
+	/// `pm <id> <text>`, `appl <strategy> <id|server> <method> <strarg> <intarg>`, `state <state>`,
+	/// `character <data>`, `vcregister <strategy> <id> <hex>`, `voice <hex>` and `repeat`.
+	Bot {
+		/// Address of the Bureau.
+		#[arg(long, default_value = "127.0.0.1:5126")]
+		addr: SocketAddr,
+
+		/// Username of the bot, numbered if there is more than one.
+		#[arg(short, long, default_value_t = ("Bot").into())]
+		username: String,
+
+		/// Avatar of the bot.
+		#[arg(long, default_value_t = ("bot.wrl").into())]
+		avatar: String,
+
+		/// Number of bots to run the script with.
+		#[arg(short, long, default_value_t = 1)]
+		count: usize,
+
+		/// File path to a script with one command per line, run before any given commands.
+		#[arg(short, long)]
+		script: Option<String>,
+
+		/// Commands to run in order.
+		commands: Vec<String>,
+	},
+
+	/// Simulate many moving users against a Bureau or WLS and report how it holds up.
+	Loadtest {
+		/// Address of the Bureau, or of the WLS if --wrl is set.
+		#[arg(long, default_value = "127.0.0.1:5126")]
+		addr: SocketAddr,
+
+		/// Ask the WLS at --addr for a Bureau of this wrl for every client.
+		#[arg(long)]
+		wrl: Option<String>,
+
+		/// Number of simulated clients.
+		#[arg(short, long, default_value_t = 100)]
+		clients: usize,
+
+		/// Clients connected per second while ramping up.
+		#[arg(long, default_value_t = 50.0)]
+		connect_rate: f32,
+
+		/// Seconds to keep running after every client has connected.
+		#[arg(short, long, default_value_t = 30.0)]
+		duration: f32,
+
+		/// Milliseconds between position updates of each client.
+		#[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+		move_interval: u64,
+
+		/// Units each client walks per second.
+		#[arg(long, default_value_t = 20.0)]
+		speed: f32,
+
+		/// Size of the square area clients walk around in.
+		#[arg(long, default_value_t = 1000.0)]
+		area: f32,
+
+		/// Chat messages per client per minute.
+		#[arg(long, default_value_t = 6.0)]
+		chat_rate: f32,
+
+		/// ApplSpecific messages per client per minute.
+		#[arg(long, default_value_t = 1.0)]
+		appl_rate: f32,
+	},
 }
 
 fn main() {
 	let args = Args::parse();
 
+	match args.command {
+		Some(Command::Bot {
+			addr,
+			username,
+			avatar,
+			count,
+			script,
+			commands,
+		}) => {
+			let options = BotOptions {
+				addr,
+				username,
+				avatar,
+				count,
+				script,
+				commands,
+			};
+
+			if let Err(err) = bot::run(options) {
+				eprintln!("Failed to run bot: {}", err);
+			}
+
+			return;
+		}
+		Some(Command::Loadtest {
+			addr,
+			wrl,
+			clients,
+			connect_rate,
+			duration,
+			move_interval,
+			speed,
+			area,
+			chat_rate,
+			appl_rate,
+		}) => {
+			let Ok(duration) = Duration::try_from_secs_f32(duration) else {
+				eprintln!(
+					"--duration has to be a number of seconds that is zero or more, not {}.",
+					duration
+				);
+				return;
+			};
+
+			let options = LoadTestOptions {
+				addr,
+				wrl,
+				clients,
+				connect_rate,
+				duration,
+				move_interval: Duration::from_millis(move_interval),
+				speed,
+				area,
+				chat_rate,
+				appl_rate,
+			};
+
+			if let Err(err) = loadtest::run(options) {
+				eprintln!("Failed to run load test: {}", err);
+			}
+
+			return;
+		}
+		None => (),
+	}
+
 	let bureau_options = BureauOptions {
 		max_players: args.max_players,
 		aura_radius: args.aura_radius,
+		aura_leave_radius: args
+			.aura_leave_radius
+			.unwrap_or(args.aura_radius)
+			.max(args.aura_radius),
+		max_aura: args.max_aura,
+		master_policy: args.master_policy,
+		sleep_timeout: args.sleep_timeout.map(Duration::from_secs),
+		emulate_master: args.emulate_master || args.master_config.is_some(),
+		pass_invalid_characters: args.pass_invalid_characters,
+		send_limits: SendLimits {
+			high_water: args.send_high_water,
+			hard_limit: args.send_hard_limit,
+		},
+		plugins: PluginOptions {
+			safe: args.safe_plugins,
+			memory_limit: args.plugin_memory.map(|mb| mb * 1024 * 1024),
+			budget: args.lua_budget.map(Duration::from_millis),
+			strikes: args.lua_strikes,
+		},
 	};
 
+	#[cfg(unix)]
+	plugin::reload_on_sighup();
+
 	let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), args.port);
 
 	if args.wls {
@@ -58,6 +292,7 @@ fn main() {
 				host_name: args.host_name,
 				max_bureaus: args.max_bureaus,
 				wrl_list: args.wrl_list,
+				master_config: args.master_config,
 				bureau_options,
 			},
 		)
@@ -77,6 +312,17 @@ fn main() {
 		}
 	};
 
+	if let Some(path) = args.master_config {
+		bureau.emulated_master = match EmulatedMaster::load(Path::new(&path)) {
+			Ok(master) => master,
+			Err(err) => {
+				eprintln!("Failed to load master config: {:#}.", err);
+
+				return;
+			}
+		};
+	}
+
 	println!("Bureau running on port: {}.", bureau.port());
 	bureau.run();
 }
@@ -1,58 +1,212 @@
 use std::{
 	net::{IpAddr, Ipv4Addr, SocketAddr},
-	time::Instant,
+	sync::{
+		mpsc::{self, Receiver, Sender, TryRecvError},
+		Arc,
+	},
+	thread,
+	time::{Duration, Instant},
 };
 
-use crate::bureau::{Bureau, BureauOptions};
+use mio::{net::TcpListener, Waker};
 
-struct BureauEx {
-	start_time: Instant,
-	inner: Bureau,
+use crate::bureau::{
+	emulated_master::EmulatedMaster, timer_wheel::TimerWheel, Bureau, BureauOptions,
+};
+
+/// How long a Bureau is kept alive without any users after being handed out.
+const LINGER_TIME: Duration = Duration::from_secs(10);
+
+/// Sent from the WLS to a Bureau thread.
+enum BureauCommand {
+	Shutdown,
+}
+
+/// Sent from a Bureau thread to the WLS.
+enum BureauStatus {
+	UserCount { port: u16, count: usize },
+	Stopped { port: u16 },
+}
+
+/// A Bureau running on its own thread.
+struct BureauHandle {
+	port: u16,
+	/// Last user count the Bureau reported.
+	users: usize,
+	/// The Bureau is kept alive until this point even if it has no users.
+	linger_until: Instant,
+	commands: Sender<BureauCommand>,
 }
 
 pub struct BureauManager {
-	bureaus: Vec<BureauEx>,
+	bureaus: Vec<BureauHandle>,
+	linger_timeouts: TimerWheel<u16>,
 	max: usize,
 	bureau_options: BureauOptions,
+	emulated_master: EmulatedMaster,
+	status_sender: Sender<BureauStatus>,
+	status: Receiver<BureauStatus>,
+	/// Woken by Bureau threads whenever they send a status.
+	waker: Arc<Waker>,
 }
 
 impl BureauManager {
 	const BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
 
-	pub fn new(max: usize, bureau_options: BureauOptions) -> Self {
+	pub fn new(
+		max: usize,
+		bureau_options: BureauOptions,
+		emulated_master: EmulatedMaster,
+		waker: Arc<Waker>,
+	) -> Self {
+		let (status_sender, status) = mpsc::channel();
+
 		Self {
 			bureaus: Vec::with_capacity(max),
+			linger_timeouts: TimerWheel::new(Duration::from_millis(100), 128),
 			max,
 			bureau_options,
+			emulated_master,
+			status_sender,
+			status,
+			waker,
 		}
 	}
 
+	/// Time until the manager next needs to be polled, even if no Bureau has sent a status.
+	pub fn next_timeout(&self) -> Option<Duration> {
+		self.linger_timeouts
+			.next_deadline()
+			.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+	}
+
+	/// Handle statuses sent by Bureaus and shut down the ones that are no longer needed.
 	pub fn poll(&mut self) {
-		self.bureaus.retain_mut(|bureau_ex| {
-			bureau_ex.inner.poll();
-			bureau_ex.start_time.elapsed().as_secs() < 10 || bureau_ex.inner.user_list.len() > 0
-		})
+		loop {
+			match self.status.try_recv() {
+				Ok(BureauStatus::UserCount { port, count }) => {
+					if let Some(handle) = self.bureaus.iter_mut().find(|h| h.port == port) {
+						handle.users = count;
+					}
+				}
+				Ok(BureauStatus::Stopped { port }) => self.bureaus.retain(|h| h.port != port),
+				Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
+			}
+		}
+
+		let now = Instant::now();
+		// Expired timeouts only wake the manager, `linger_until` decides if the Bureau may stop.
+		self.linger_timeouts.expire(now);
+
+		self.bureaus.retain(|handle| {
+			if handle.users > 0 || now < handle.linger_until {
+				return true;
+			}
+
+			let _ = handle.commands.send(BureauCommand::Shutdown);
+			false
+		});
 	}
 
+	/// Get the port of a Bureau with room for another user, starting a new one if needed.
 	pub fn available(&mut self) -> Option<u16> {
-		if let Some(bureau_ex) = self.bureaus.iter().find(|bureau_ex| {
-			bureau_ex.inner.user_list.len() < bureau_ex.inner.options.max_players as usize
-		}) {
-			return Some(bureau_ex.inner.port());
-		}
+		let max_players = self.bureau_options.max_players as usize;
 
-		if self.bureaus.len() < self.max {
-			let bureau = Bureau::new(Self::BIND_ADDR, self.bureau_options).ok()?;
-			let port = bureau.port();
+		let i = match self.bureaus.iter().position(|h| h.users < max_players) {
+			Some(i) => i,
+			None if self.bureaus.len() < self.max => {
+				let handle = self.spawn()?;
+				self.bureaus.push(handle);
+				self.bureaus.len() - 1
+			}
+			None => return None,
+		};
+		let handle = &mut self.bureaus[i];
 
-			self.bureaus.push(BureauEx {
-				start_time: Instant::now(),
-				inner: bureau,
+		// Give the user that's about to connect time to do so.
+		handle.linger_until = Instant::now() + LINGER_TIME;
+		self.linger_timeouts.insert_after(LINGER_TIME, handle.port);
+
+		Some(handle.port)
+	}
+
+	/// Start a new Bureau on its own thread.
+	/// The listener is bound here so the port is known right away, everything else about the
+	/// Bureau is created on its thread since the Lua state can't be sent between threads.
+	fn spawn(&self) -> Option<BureauHandle> {
+		let listener = match TcpListener::bind(Self::BIND_ADDR) {
+			Ok(listener) => listener,
+			Err(e) => {
+				eprintln!("Failed to bind Bureau: {}", e);
+				return None;
+			}
+		};
+		let port = listener.local_addr().ok()?.port();
+
+		let (commands, command_receiver) = mpsc::channel();
+		let status = self.status_sender.clone();
+		let waker = self.waker.clone();
+		let options = self.bureau_options;
+		let emulated_master = self.emulated_master.clone();
+
+		let spawned = thread::Builder::new()
+			.name(format!("bureau-{}", port))
+			.spawn(move || {
+				match Bureau::with_listener(listener, options) {
+					Ok(mut bureau) => {
+						bureau.emulated_master = emulated_master;
+						run_bureau(bureau, command_receiver, &status, &waker);
+					}
+					Err(e) => eprintln!("Failed to run Bureau on port {}: {}", port, e),
+				}
+
+				let _ = status.send(BureauStatus::Stopped { port });
+				let _ = waker.wake();
 			});
+		if let Err(e) = spawned {
+			eprintln!("Failed to spawn Bureau thread: {}", e);
+			return None;
+		}
 
-			return Some(port);
+		Some(BureauHandle {
+			port,
+			users: 0,
+			linger_until: Instant::now() + LINGER_TIME,
+			commands,
+		})
+	}
+}
+
+/// Poll a Bureau until the WLS tells it to shut down, reporting its user count whenever it changes.
+fn run_bureau(
+	mut bureau: Bureau,
+	commands: Receiver<BureauCommand>,
+	status: &Sender<BureauStatus>,
+	waker: &Waker,
+) {
+	let port = bureau.port();
+	let mut reported = 0;
+
+	loop {
+		// `next_timeout` is never longer than the think interval, so commands are seen quickly.
+		match commands.try_recv() {
+			Ok(BureauCommand::Shutdown) | Err(TryRecvError::Disconnected) => return,
+			Err(TryRecvError::Empty) => (),
 		}
 
-		None
+		bureau.poll(Some(bureau.next_timeout()));
+
+		let count = bureau.user_list.len();
+		if count != reported {
+			reported = count;
+
+			if status
+				.send(BureauStatus::UserCount { port, count })
+				.is_err()
+			{
+				return;
+			}
+			let _ = waker.wake();
+		}
 	}
 }
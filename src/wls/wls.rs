@@ -2,12 +2,18 @@ use std::{
 	collections::HashMap,
 	fs::File,
 	io::{self, BufRead, BufReader, ErrorKind, Read, Write},
-	net::{SocketAddr, TcpListener},
-	thread,
+	net::SocketAddr,
+	path::Path,
+	sync::Arc,
 	time::{Duration, Instant},
 };
 
-use crate::bureau::BureauOptions;
+use mio::{
+	net::{TcpListener, TcpStream},
+	Events, Interest, Poll, Token, Waker,
+};
+
+use crate::bureau::{emulated_master::EmulatedMaster, timer_wheel::TimerWheel, BureauOptions};
 
 use super::bureau_manager::BureauManager;
 
@@ -15,6 +21,8 @@ pub struct WlsOptions {
 	pub host_name: String,
 	pub max_bureaus: usize,
 	pub wrl_list: Option<String>,
+	/// Directory with the emulated master config of each wrl, as `<wrl>.txt`.
+	pub master_config: Option<String>,
 	pub bureau_options: BureauOptions,
 }
 
@@ -30,9 +38,21 @@ fn default_wrls() -> Vec<String> {
 	]
 }
 
+const LISTENER: Token = Token(0);
+// Bureau threads wake the WLS with this token whenever they send a status.
+const BUREAU: Token = Token(1);
+const REQUEST_BASE: usize = 2;
+
+/// How long a socket has to send its request before being dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub fn run(addr: SocketAddr, options: WlsOptions) -> io::Result<()> {
-	let listener = TcpListener::bind(addr)?;
-	listener.set_nonblocking(true)?;
+	let mut poll = Poll::new()?;
+	let mut events = Events::with_capacity(1024);
+
+	let mut listener = TcpListener::bind(addr)?;
+	poll.registry()
+		.register(&mut listener, LISTENER, Interest::READABLE)?;
 	let wls_port = listener.local_addr()?.port();
 
 	let wrls = match &options.wrl_list {
@@ -43,69 +63,155 @@ pub fn run(addr: SocketAddr, options: WlsOptions) -> io::Result<()> {
 		None => default_wrls(),
 	};
 
+	let waker = Arc::new(Waker::new(poll.registry(), BUREAU)?);
+
 	let mut managers = HashMap::with_capacity(wrls.len());
 	for wrl in wrls {
+		let emulated_master = match &options.master_config {
+			Some(dir) => load_master_config(Path::new(dir), &wrl),
+			None => EmulatedMaster::default(),
+		};
+
 		managers.insert(
 			wrl,
-			BureauManager::new(options.max_bureaus, options.bureau_options),
+			BureauManager::new(
+				options.max_bureaus,
+				options.bureau_options,
+				emulated_master,
+				waker.clone(),
+			),
 		);
 	}
 
-	let mut queue = Vec::new();
+	let mut queue = HashMap::new();
+	let mut next_token = REQUEST_BASE;
+	let mut timeouts = TimerWheel::new(Duration::from_millis(100), 128);
 
 	println!("WLS running on port: {}.", wls_port);
 	loop {
-		if let Ok((socket, _)) = listener.accept() {
-			if let Ok(()) = socket.set_nonblocking(true) {
-				queue.push((Instant::now(), socket));
+		let now = Instant::now();
+		let timeout = managers
+			.values()
+			.filter_map(BureauManager::next_timeout)
+			.chain(
+				timeouts
+					.next_deadline()
+					.map(|deadline| deadline.saturating_duration_since(now)),
+			)
+			.min();
+
+		if let Err(err) = poll.poll(&mut events, timeout) {
+			if err.kind() != ErrorKind::Interrupted {
+				return Err(err);
 			}
 		}
 
-		queue.retain_mut(|(connect_time, socket)| {
-			let mut buf = [0; 256];
-			let n = match socket.read(&mut buf) {
-				Ok(n) => n,
-				Err(e) if e.kind() == ErrorKind::WouldBlock => {
-					return connect_time.elapsed().as_secs() < 10;
+		for event in events.iter() {
+			match event.token() {
+				LISTENER => loop {
+					let (mut socket, _) = match listener.accept() {
+						Ok(accepted) => accepted,
+						Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+						Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+						Err(e) => {
+							eprintln!("Failed to accept connection: {}", e);
+							break;
+						}
+					};
+
+					let token = Token(next_token);
+					next_token = next_token.checked_add(1).unwrap_or(REQUEST_BASE);
+
+					if poll
+						.registry()
+						.register(&mut socket, token, Interest::READABLE)
+						.is_ok()
+					{
+						queue.insert(token, socket);
+						timeouts.insert_after(REQUEST_TIMEOUT, token);
+					}
+				},
+				BUREAU => (),
+				token => {
+					let Some(socket) = queue.get_mut(&token) else {
+						continue;
+					};
+
+					let mut buf = [0; 256];
+					let n = match socket.read(&mut buf) {
+						Ok(n) => n,
+						Err(e)
+							if e.kind() == ErrorKind::WouldBlock
+								|| e.kind() == ErrorKind::Interrupted =>
+						{
+							continue;
+						}
+						Err(_) => {
+							queue.remove(&token);
+							continue;
+						}
+					};
+
+					let mut socket = queue.remove(&token).unwrap();
+					handle_request(&mut socket, &buf[..n], &mut managers, &options);
 				}
-				Err(_) => return false,
-			};
+			}
+		}
+
+		for token in timeouts.expire(Instant::now()) {
+			queue.remove(&token);
+		}
 
-			let Ok(request) = String::from_utf8(buf[..n].to_vec()) else {
-				return false;
-			};
+		for manager in managers.values_mut() {
+			manager.poll();
+		}
+	}
+}
 
-			let mut split = request.split(',');
+/// Load the emulated master config of `wrl`, a wrl without one gets an empty config.
+fn load_master_config(dir: &Path, wrl: &str) -> EmulatedMaster {
+	let path = dir.join(format!("{}.txt", wrl));
+	if !path.is_file() {
+		return EmulatedMaster::default();
+	}
 
-			let Some("f") = split.next() else {
-				return false;
-			};
+	EmulatedMaster::load(&path).unwrap_or_else(|err| {
+		eprintln!("Failed to load master config: {:#}.", err);
+		EmulatedMaster::default()
+	})
+}
 
-			if split.next().is_none() {
-				return false;
-			}
+fn handle_request(
+	socket: &mut TcpStream,
+	buf: &[u8],
+	managers: &mut HashMap<String, BureauManager>,
+	options: &WlsOptions,
+) {
+	let Ok(request) = String::from_utf8(buf.to_vec()) else {
+		return;
+	};
 
-			let Some(wrl) = split.next() else {
-				return false;
-			};
+	let mut split = request.split(',');
 
-			let Some(port) = (match managers.get_mut(wrl) {
-				Some(manager) => manager.available(),
-				None => None,
-			}) else {
-				let _ = socket.write(b"f,9");
-				return false;
-			};
+	let Some("f") = split.next() else {
+		return;
+	};
 
-			let _ = socket.write(format!("f,0,{},{}\0", options.host_name, port).as_bytes());
+	if split.next().is_none() {
+		return;
+	}
 
-			false
-		});
+	let Some(wrl) = split.next() else {
+		return;
+	};
 
-		for manager in managers.values_mut() {
-			manager.poll();
-		}
+	let Some(port) = (match managers.get_mut(wrl) {
+		Some(manager) => manager.available(),
+		None => None,
+	}) else {
+		let _ = socket.write(b"f,9");
+		return;
+	};
 
-		thread::sleep(Duration::from_millis(100));
-	}
+	let _ = socket.write(format!("f,0,{},{}\0", options.host_name, port).as_bytes());
 }